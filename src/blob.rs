@@ -0,0 +1,103 @@
+//! Binary records interleaved with defmt frames.
+//!
+//! [`log_blob`] writes a tagged binary record, such as a sensor sample, straight to the
+//! buffers, without the per-byte cost of formatting it with defmt:
+//!
+//! ```ignore
+//! defmtusb::log_blob(SENSOR_FRAME, &sample.to_bytes());
+//! ```
+//!
+//! Each record is written as a single frame:
+//!
+//! - `0x00`, ending any partial frame before it.
+//! - The two [`MARKER`] bytes, `BL`.
+//! - The tag, as a little-endian `u16`, followed by the data, together encoded with
+//!   [COBS] so that they hold no `0x00` byte.
+//! - `0x00`, so that the record is delimited like a defmt frame.
+//!
+//! The host should split the stream on `0x00` bytes, decode the frames starting with
+//! the marker as records, and pass the rest to the defmt decoder. In Python, with the
+//! `cobs` package:
+//!
+//! ```python
+//! for frame in stream.split(b"\x00"):
+//!     if frame.startswith(b"BL"):
+//!         record = cobs.decode(frame[2:])
+//!         tag, data = int.from_bytes(record[:2], "little"), record[2:]
+//!     elif frame:
+//!         decode_defmt(frame + b"\x00")
+//! ```
+//!
+//! Records are buffered like defmt frames: a record is kept whole or dropped whole,
+//! following the [`Mode`](crate::Mode) and [`OverflowPolicy`](crate::OverflowPolicy),
+//! and one longer than a buffer is dropped or truncated following the
+//! [`LongFramePolicy`](crate::LongFramePolicy). A record takes seven bytes more than
+//! its data, plus one for every 254 bytes of data. Records rely on the rzcobs frame
+//! delimiters, so they cannot be used with the `encoding-raw` feature.
+//!
+//! [COBS]: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
+
+#[cfg(not(feature = "disabled"))]
+use crate::controller::CONTROLLER;
+
+/// Marker bytes identifying a binary record.
+pub const MARKER: [u8; 2] = *b"BL";
+
+/// Longest run of non-zero bytes in a COBS block.
+const MAX_RUN: usize = 254;
+
+/// Writes a binary record with the given tag to the logger, as a frame the host can
+/// separate from defmt frames.
+///
+/// The tag identifies the kind of record to the host. The record is written within a
+/// critical section, like a defmt log call.
+///
+/// # Panics
+///
+/// Panics if called from within a defmt log call, for instance from a `Format`
+/// implementation, unless the `drop-reentrant-frames` feature is enabled, in which case
+/// the record is dropped.
+pub fn log_blob(tag: u16, data: &[u8]) {
+    #[cfg(not(feature = "disabled"))]
+    crate::USB_ENCODER.write_frame(|| {
+        // SAFETY: `write_frame` calls this within a critical section, between the start
+        // and end of a frame.
+        unsafe {
+            CONTROLLER.write(&[0x00]);
+            CONTROLLER.write(&MARKER);
+            write_cobs(tag.to_le_bytes().iter().chain(data).copied());
+            CONTROLLER.write(&[0x00]);
+        }
+    });
+}
+
+/// Write `bytes` encoded with COBS, in blocks of a code byte followed by a run of
+/// non-zero bytes.
+///
+/// # Safety
+///
+/// Must be called from within a critical section, between the start and end of a
+/// frame.
+#[cfg(not(feature = "disabled"))]
+unsafe fn write_cobs(bytes: impl Iterator<Item = u8>) {
+    // The code byte, giving the length of the block, followed by the run.
+    let mut block = [0; MAX_RUN + 1];
+    let mut len = 1;
+    for byte in bytes {
+        if byte != 0x00 {
+            block[len] = byte;
+            len += 1;
+            if len < block.len() {
+                continue;
+            }
+        }
+        // The block ends at a zero byte, which it stands for, or once full.
+        block[0] = len as u8;
+        // SAFETY: Guaranteed by the caller.
+        unsafe { CONTROLLER.write(&block[..len]) };
+        len = 1;
+    }
+    block[0] = len as u8;
+    // SAFETY: Guaranteed by the caller.
+    unsafe { CONTROLLER.write(&block[..len]) };
+}
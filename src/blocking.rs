@@ -0,0 +1,108 @@
+//! Blocking flush of the logger, for use when the async logger task cannot run.
+
+use core::{
+    cell::Cell,
+    future::Future,
+    marker::PhantomData,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use critical_section::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::{controller::CONTROLLER, task::flush_once, transport::LogTransport};
+
+/// Maximum time a blocking flush waits for the host to read the buffers.
+const BLOCKING_FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The transport lent by the logger task, if any.
+static REGISTRATION: Mutex<Cell<Option<Registration>>> = Mutex::new(Cell::new(None));
+
+/// A type-erased pointer to the logger task's transport and the function to drain it.
+#[derive(Clone, Copy)]
+struct Registration {
+    /// Pointer to the transport lent by the logger task.
+    transport: *mut (),
+    /// Monomorphised `drain` for the type of the transport.
+    drain: unsafe fn(*mut (), Instant),
+}
+
+// SAFETY: The transport pointer is only dereferenced in `flush`, while the logger task
+// is suspended and has lent it out through a `Registered` guard.
+unsafe impl Send for Registration {}
+
+/// Guard lending the logger task's transport to the blocking flush.
+///
+/// The transport is available to `flush` until the guard is dropped.
+pub(crate) struct Registered<'a> {
+    _transport: PhantomData<&'a mut ()>,
+}
+
+impl Drop for Registered<'_> {
+    fn drop(&mut self) {
+        critical_section::with(|cs| REGISTRATION.borrow(cs).set(None));
+    }
+}
+
+/// Lend the transport to the blocking flush until the returned guard is dropped.
+///
+/// The logger task does this only while it is waiting between flushes, so that a
+/// blocking flush never interleaves its packets with a transfer already in progress.
+pub(crate) fn register<T: LogTransport>(transport: &mut T) -> Registered<'_> {
+    let registration = Registration {
+        transport: (transport as *mut T).cast(),
+        drain: drain::<T>,
+    };
+    critical_section::with(|cs| REGISTRATION.borrow(cs).set(Some(registration)));
+    Registered {
+        _transport: PhantomData,
+    }
+}
+
+/// Flush all buffers, busy-polling the transport until they are empty.
+///
+/// Gives up once [`BLOCKING_FLUSH_TIMEOUT`] has elapsed, so it does not hang if the
+/// host is not reading. Does nothing if the logger task has not lent its transport,
+/// for instance because it is in the middle of a transfer or disconnected.
+pub(crate) fn flush() {
+    let Some(registration) = critical_section::with(|cs| REGISTRATION.borrow(cs).get()) else {
+        return;
+    };
+    let deadline = Instant::now() + BLOCKING_FLUSH_TIMEOUT;
+    // SAFETY: The registration is only present while the logger task holds a `Registered`
+    // guard, so the transport is valid and not otherwise in use.
+    unsafe { (registration.drain)(registration.transport, deadline) };
+}
+
+/// Flush buffers with the given transport until none need flushing or the deadline
+/// passes.
+///
+/// # Safety
+///
+/// `transport` must point to a valid `T` that is not otherwise in use.
+unsafe fn drain<T: LogTransport>(transport: *mut (), deadline: Instant) {
+    // SAFETY: Guaranteed by the caller.
+    let transport = unsafe { &mut *transport.cast::<T>() };
+    while CONTROLLER.needs_flush() {
+        match block_on(flush_once(transport), deadline) {
+            Some(Ok(_)) => (),
+            // Disconnected, or timed out.
+            Some(Err(_)) | None => return,
+        }
+    }
+}
+
+/// Poll a future to completion without an executor, giving up at the deadline.
+fn block_on<F: Future>(fut: F, deadline: Instant) -> Option<F::Output> {
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = fut.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! Buffer capturing the frames logged during boot, enabled with a `bootbuffer-*`
+//! feature.
+//!
+//! Until the host first attaches, frames are written to the boot buffer rather than
+//! the rotating buffers. The boot buffer is closed once the host attaches, or once a
+//! frame does not fit in it, and from then on frames go to the rotating buffers. Once
+//! attached, the logger sends the boot buffer first, then the rotating buffers, so
+//! frames are sent in the order they were logged. The boot buffer is never used again
+//! afterwards.
+//!
+//! Without a `bootbuffer-*` feature, the buffer is empty and never accepts a frame.
+
+use core::{cell::UnsafeCell, sync::atomic::Ordering};
+
+use portable_atomic::{AtomicBool, AtomicUsize};
+
+/// Size of the boot buffer, which is not used with the `disabled` feature.
+#[cfg(any(
+    not(any(
+        feature = "bootbuffer-1024",
+        feature = "bootbuffer-2048",
+        feature = "bootbuffer-4096"
+    )),
+    feature = "disabled"
+))]
+pub const BOOT_BUFFER_SIZE: usize = 0;
+
+#[cfg(all(feature = "bootbuffer-1024", not(feature = "disabled")))]
+pub const BOOT_BUFFER_SIZE: usize = 1024;
+
+#[cfg(all(feature = "bootbuffer-2048", not(feature = "disabled")))]
+pub const BOOT_BUFFER_SIZE: usize = 2048;
+
+#[cfg(all(feature = "bootbuffer-4096", not(feature = "disabled")))]
+pub const BOOT_BUFFER_SIZE: usize = 4096;
+
+/// The boot buffer of the logger, placed with the rotating buffers by the
+/// `buffer-section` feature.
+#[cfg_attr(feature = "buffer-section", link_section = ".defmtusb_buffers")]
+static BOOT: BootBuffer = BootBuffer::new();
+
+/// Buffer of the frames logged before the host first attaches.
+struct BootBuffer {
+    /// No more frames are written to the buffer.
+    closed: AtomicBool,
+    /// Number of bytes held.
+    cursor: AtomicUsize,
+    /// Cursor at which the frame being written starts.
+    frame_start: AtomicUsize,
+    /// Buffered data.
+    data: UnsafeCell<[u8; BOOT_BUFFER_SIZE]>,
+}
+
+// Sync is required for types in static variables.
+//
+// SAFETY: The data is only written within a critical section while the buffer is open,
+// and only read once it is closed.
+unsafe impl Sync for BootBuffer {}
+
+impl BootBuffer {
+    /// Static initializer.
+    const fn new() -> Self {
+        Self {
+            // Without a boot buffer, there is never anything to write or send.
+            closed: AtomicBool::new(BOOT_BUFFER_SIZE == 0),
+            cursor: AtomicUsize::new(0),
+            frame_start: AtomicUsize::new(0),
+            data: UnsafeCell::new([0; BOOT_BUFFER_SIZE]),
+        }
+    }
+}
+
+/// Start a frame in the boot buffer, if it is open.
+///
+/// # Safety
+///
+/// Must be called from within the defmt critical section.
+#[inline]
+pub(crate) unsafe fn start_frame() {
+    if !BOOT.closed.load(Ordering::Relaxed) {
+        BOOT.frame_start
+            .store(BOOT.cursor.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// Result of writing to the boot buffer.
+pub(crate) enum Write {
+    /// The bytes were written to the boot buffer.
+    Written,
+    /// The boot buffer is closed, so the bytes must be written to the rotating buffers.
+    Closed,
+    /// The bytes did not fit, so the boot buffer was closed. The part of the frame
+    /// already written to it was removed, and must be written to the rotating buffers
+    /// along with the bytes.
+    Full(&'static [u8]),
+}
+
+/// Write bytes of the frame being logged to the boot buffer, if it is open.
+///
+/// # Safety
+///
+/// Must be called from within the defmt critical section, between `start_frame` and the
+/// end of the frame.
+#[inline]
+pub(crate) unsafe fn write(bytes: &[u8]) -> Write {
+    if BOOT.closed.load(Ordering::Relaxed) {
+        return Write::Closed;
+    }
+    let cursor = BOOT.cursor.load(Ordering::Relaxed);
+    if cursor + bytes.len() > BOOT_BUFFER_SIZE {
+        // Close the buffer, handing the partial frame over to the rotating buffers.
+        let frame_start = BOOT.frame_start.load(Ordering::Relaxed).min(cursor);
+        BOOT.cursor.store(frame_start, Ordering::Relaxed);
+        BOOT.closed.store(true, Ordering::Relaxed);
+        // SAFETY: The buffer is now closed, so the data is no longer written.
+        let data = unsafe { &*BOOT.data.get() };
+        return Write::Full(&data[frame_start..cursor]);
+    }
+    // SAFETY: We are in a critical section, guaranteed by the caller, and the buffer is
+    // open, so nothing reads the data.
+    let data = unsafe { &mut *BOOT.data.get() };
+    data[cursor..cursor + bytes.len()].copy_from_slice(bytes);
+    BOOT.cursor.store(cursor + bytes.len(), Ordering::Relaxed);
+    Write::Written
+}
+
+/// Returns the length of the frame being written to the boot buffer, or `None` if it is
+/// closed.
+///
+/// # Safety
+///
+/// Must be called from within the defmt critical section, before the end of the frame.
+#[cfg(feature = "metrics")]
+#[inline]
+pub(crate) unsafe fn frame_len() -> Option<usize> {
+    if BOOT.closed.load(Ordering::Relaxed) {
+        return None;
+    }
+    let start = BOOT.frame_start.load(Ordering::Relaxed);
+    Some(BOOT.cursor.load(Ordering::Relaxed).saturating_sub(start))
+}
+
+/// Close the boot buffer, so that it is sent and no longer written to.
+///
+/// This is done in a critical section, so that it cannot happen within a frame.
+pub(crate) fn close() {
+    critical_section::with(|_| BOOT.closed.store(true, Ordering::Relaxed));
+}
+
+/// Discard the frames in the boot buffer, closing it.
+pub(crate) fn discard() {
+    critical_section::with(|_| {
+        BOOT.closed.store(true, Ordering::Relaxed);
+        BOOT.cursor.store(0, Ordering::Relaxed);
+    });
+}
+
+/// Returns the contents of the boot buffer if it is closed and waiting to be sent.
+pub(crate) fn pending() -> Option<&'static [u8]> {
+    if !BOOT.closed.load(Ordering::Relaxed) {
+        return None;
+    }
+    let len = BOOT.cursor.load(Ordering::Relaxed);
+    // SAFETY: The buffer is closed, so the data is never written again.
+    let data = unsafe { &*BOOT.data.get() };
+    (len > 0).then(|| &data[..len])
+}
+
+/// Release the boot buffer once it has been sent, or sending it failed.
+pub(crate) fn finish() {
+    BOOT.cursor.store(0, Ordering::Relaxed);
+}
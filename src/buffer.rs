@@ -1,39 +1,63 @@
 //! Buffer of the `defmt` logger.
 
-/// The size of the buffer.
+use embassy_time::{Duration, Instant};
+
+/// The default size of the buffer.
 #[cfg(feature = "buffersize-64")]
-const BUFFERSIZE: usize = 64;
+pub(super) const BUFFERSIZE: usize = 64;
 
 #[cfg(feature = "buffersize-128")]
-const BUFFERSIZE: usize = 128;
+pub(super) const BUFFERSIZE: usize = 128;
 
 #[cfg(feature = "buffersize-256")]
-const BUFFERSIZE: usize = 256;
+pub(super) const BUFFERSIZE: usize = 256;
 
 #[cfg(feature = "buffersize-512")]
-const BUFFERSIZE: usize = 512;
+pub(super) const BUFFERSIZE: usize = 512;
 
 #[cfg(feature = "buffersize-1024")]
-const BUFFERSIZE: usize = 1024;
-
-pub(super) struct LogBuffer {
+pub(super) const BUFFERSIZE: usize = 1024;
+
+// Buffers must be at least as large as a packet of a full-speed bulk endpoint, as
+// packets may not be larger than a buffer. A buffer holds at most `BUFFERSIZE - 1` bytes
+// (see `LogBuffer::accepts`), so with `buffersize-64` every buffer is sent as a single
+// short packet, and larger buffers are needed for full packets.
+const _: () = assert!(
+    BUFFERSIZE >= 64,
+    "The buffer size must be at least the full-speed packet size of 64 bytes"
+);
+
+/// A buffer holding `SIZE` bytes of defmt frames.
+///
+/// `SIZE` defaults to the size selected by the `buffersize-*` features.
+pub(super) struct LogBuffer<const SIZE: usize = BUFFERSIZE> {
     /// Current state of the buffer.
     state: BufferState,
 
     /// Current cursor into the buffer.
     pub(super) cursor: usize,
 
+    /// Time at which the logger task first saw data in the buffer since it was last reset.
+    ///
+    /// This is recorded by the logger task rather than when writing, so that the write
+    /// path never reads the clock.
+    first_seen: Option<Instant>,
+
     /// Buffered data.
-    pub(super) data: [u8; BUFFERSIZE],
+    pub(super) data: [u8; SIZE],
 }
 
-impl LogBuffer {
+impl<const SIZE: usize> LogBuffer<SIZE> {
+    /// The number of bytes the buffer can hold.
+    pub(super) const CAPACITY: usize = SIZE;
+
     /// Static initializer.
     pub const fn new() -> Self {
         Self {
             state: BufferState::Active,
             cursor: 0,
-            data: [0u8; BUFFERSIZE],
+            first_seen: None,
+            data: [0u8; SIZE],
         }
     }
 
@@ -47,39 +71,40 @@ impl LogBuffer {
     pub(super) fn reset(&mut self) {
         self.state = BufferState::Active;
         self.cursor = 0;
+        self.first_seen = None;
     }
 
-    /// Writes to the buffer.
-    pub(super) fn write(&mut self, bytes: &[u8]) {
+    /// Writes to the buffer, returning the number of bytes written.
+    ///
+    /// At most the remaining capacity is written, so callers should check `accepts`
+    /// first to avoid losing bytes. Nothing is written to a buffer being flushed.
+    pub(super) fn write(&mut self, bytes: &[u8]) -> usize {
         // If not active, return immediately.
         if self.is_flushing() {
-            return;
+            return 0;
         }
 
-        // Get the minimum size.
-        // TODO: if bytes.len() > remaining buffer, some bytes won't be written.
-        // This should at least be reported to the caller.
-        let n = core::cmp::min(BUFFERSIZE - self.cursor, bytes.len());
+        // Clamp to the remaining capacity.
+        let n = core::cmp::min(SIZE - self.cursor, bytes.len());
 
         // Write the bytes.
         self.data[self.cursor..self.cursor + n].copy_from_slice(&bytes[0..n]);
 
         // Increment the cursor.
         self.cursor += n;
+        n
+    }
 
-        // If limit reached, set as flush.
-        //
-        // TODO: Is this necessary? Why -2? Is it to avoid the max-packet-size transfer
-        // needing a shorter transfer afterwards?
-        if self.cursor >= (BUFFERSIZE - 2) {
-            self.state = BufferState::Flush;
-        }
+    /// Discards the bytes after the first `len`.
+    #[inline]
+    pub(super) fn truncate(&mut self, len: usize) {
+        self.cursor = self.cursor.min(len);
     }
 
     /// Returns `true` if the given number of bytes can be written to the buffer.
     #[inline]
     pub(super) fn accepts(&self, n: usize) -> bool {
-        ((self.cursor + n) < BUFFERSIZE) & self.writable()
+        ((self.cursor + n) < SIZE) & self.writable()
     }
 
     /// Returns `true` if the buffer can be written to.
@@ -88,6 +113,19 @@ impl LogBuffer {
         self.state == BufferState::Active
     }
 
+    /// Returns `true` if the buffer has held data for at least `max_age` at time `now`.
+    ///
+    /// The age is measured from the first call to this function that finds data in the
+    /// buffer, so it is only as precise as the interval between calls. An empty buffer is
+    /// never considered stale.
+    pub(super) fn is_stale(&mut self, max_age: Duration, now: Instant) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        let first_seen = *self.first_seen.get_or_insert(now);
+        now.saturating_duration_since(first_seen) >= max_age
+    }
+
     /// Returns `true` if the buffer is being flushed.
     #[inline]
     pub(super) fn is_flushing(&self) -> bool {
@@ -104,3 +142,36 @@ enum BufferState {
     /// This buffer is full and must be flushed.
     Flush = 1,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::LogBuffer;
+
+    #[test]
+    fn write_longer_than_the_buffer_is_clamped() {
+        let mut buffer = LogBuffer::<8>::new();
+        assert_eq!(buffer.write(&[1; 5]), 5);
+
+        // Only the remaining capacity is written, and the rest is left to the caller.
+        assert_eq!(buffer.write(&[2; 10]), 3);
+        assert_eq!(buffer.cursor, 8);
+        assert_eq!(buffer.data, [1, 1, 1, 1, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn write_to_a_full_buffer_writes_nothing() {
+        let mut buffer = LogBuffer::<8>::new();
+        assert_eq!(buffer.write(&[1; 20]), 8);
+        assert_eq!(buffer.write(&[2; 4]), 0);
+        assert_eq!(buffer.data, [1; 8]);
+    }
+
+    #[test]
+    fn write_to_a_flushing_buffer_writes_nothing() {
+        let mut buffer = LogBuffer::<8>::new();
+        buffer.write(&[1; 2]);
+        buffer.flush();
+        assert_eq!(buffer.write(&[2; 4]), 0);
+        assert_eq!(buffer.cursor, 2);
+    }
+}
@@ -0,0 +1,151 @@
+//! Tagging of log frames with a channel, enabled with the `channels` feature.
+//!
+//! defmt has a single global logger, so channels are selected around log calls rather
+//! than by using a different logger:
+//!
+//! ```ignore
+//! const MOTOR: ChannelLogger = ChannelLogger::new(1);
+//!
+//! MOTOR.log(|| defmt::info!("speed: {}", speed));
+//! ```
+//!
+//! Frames logged outside of [`ChannelLogger::log`] belong to channel 0. Before each
+//! frame whose channel differs from the previous frame in the same buffer (and before
+//! the first frame of every buffer), a tag of [`TAG_LEN`] bytes is inserted:
+//!
+//! - `0x00`, ending any partial frame before it.
+//! - The two [`MARKER`] bytes, `CH`.
+//! - The channel, with the high bit set.
+//! - `0x00`, so that the tag is delimited like a defmt frame.
+//!
+//! Every frame after a tag belongs to its channel, until the next tag. The host should
+//! remove the tags and split the frames by channel before decoding them. With the
+//! `compression` feature, tags are added before compression.
+
+use core::sync::atomic::Ordering;
+
+use portable_atomic::AtomicU8;
+
+/// Marker bytes identifying a tag.
+pub const MARKER: [u8; 2] = *b"CH";
+
+/// Length of a tag in bytes.
+pub const TAG_LEN: usize = 5;
+
+/// Channel of the frames currently being logged.
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+
+/// Logs to a channel, so that the host can separate its frames from other logs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChannelLogger(u8);
+
+impl ChannelLogger {
+    /// Creates a logger for the given channel.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `channel` is not below 128.
+    pub const fn new(channel: u8) -> Self {
+        assert!(channel < 0x80, "Channels must be below 128");
+        Self(channel)
+    }
+
+    /// Returns the channel of the logger.
+    pub const fn channel(&self) -> u8 {
+        self.0
+    }
+
+    /// Calls `f`, tagging the frames it logs with this logger's channel.
+    ///
+    /// `f` runs inside a critical section, so that frames logged by interrupts are not
+    /// tagged with this channel. It should only log, and be kept short.
+    pub fn log<R>(&self, f: impl FnOnce() -> R) -> R {
+        critical_section::with(|_| {
+            let previous = CURRENT.load(Ordering::Relaxed);
+            CURRENT.store(self.0, Ordering::Relaxed);
+            let result = f();
+            CURRENT.store(previous, Ordering::Relaxed);
+            result
+        })
+    }
+}
+
+/// Returns the channel of the frames currently being logged.
+#[inline]
+pub(crate) fn current() -> u8 {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Returns the tag for the given channel.
+#[inline]
+pub(crate) fn tag(channel: u8) -> [u8; TAG_LEN] {
+    [0x00, MARKER[0], MARKER[1], 0x80 | channel, 0x00]
+}
+
+#[cfg(all(test, not(feature = "disabled")))]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+
+    use super::*;
+    use crate::testing::{host, MemoryTransport};
+
+    const MOTOR: ChannelLogger = ChannelLogger::new(1);
+    const SENSOR: ChannelLogger = ChannelLogger::new(2);
+
+    #[test]
+    fn tags_hold_the_channel_with_the_high_bit_set() {
+        assert_eq!(tag(0), [0, b'C', b'H', 0x80, 0]);
+        assert_eq!(tag(0x7F), [0, b'C', b'H', 0xFF, 0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Channels must be below 128")]
+    fn channels_above_127_panic() {
+        ChannelLogger::new(0x80);
+    }
+
+    #[test]
+    fn log_restores_the_previous_channel() {
+        let _lock = host::lock();
+        MOTOR.log(|| {
+            assert_eq!(current(), 1);
+            SENSOR.log(|| assert_eq!(current(), 2));
+            assert_eq!(current(), 1);
+        });
+        assert_eq!(current(), 0);
+    }
+
+    #[test]
+    fn frames_are_tagged_when_the_channel_changes() {
+        let _lock = host::lock();
+        crate::write_raw_frame(&[1; 10]);
+        MOTOR.log(|| {
+            crate::write_raw_frame(&[2; 10]);
+            crate::write_raw_frame(&[3; 5]);
+        });
+        crate::write_raw_frame(&[4; 5]);
+        let mut transport = MemoryTransport::new(64);
+        embassy_futures::block_on(crate::flush_all(&mut transport)).unwrap();
+
+        #[cfg(feature = "compression")]
+        let bytes = host::decompress(&transport.bytes);
+        #[cfg(not(feature = "compression"))]
+        let bytes = transport.bytes.clone();
+        #[cfg(feature = "sequence-numbers")]
+        let bytes = host::strip_headers(&bytes).1;
+        assert_eq!(
+            host::split_channels(&bytes),
+            [
+                (0, vec![1; 10]),
+                (1, [[2; 10].as_slice(), &[3; 5]].concat()),
+                (0, vec![4; 5]),
+            ]
+        );
+        assert_eq!(
+            host::decode(&transport.bytes),
+            [[1; 10].as_slice(), &[2; 10], &[3; 5], &[4; 5]].concat()
+        );
+    }
+}
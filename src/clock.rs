@@ -0,0 +1,59 @@
+//! Time source of the logger task's flush loop.
+//!
+//! The flush interval, write timeout, retry backoff, startup delay and maximum latency
+//! are all measured with a [`Clock`]. The logger uses [`EmbassyClock`], backed by
+//! `embassy_time`, unless given another with
+//! [`logger_with_clock`](crate::logger_with_clock), such as the virtual clock of the
+//! `testing` feature, so that timing can be tested quickly and deterministically.
+
+use core::future::Future;
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+
+/// A source of time for the logger task.
+#[allow(async_fn_in_trait)]
+pub trait Clock {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Wait for `duration` to elapse.
+    async fn after(&self, duration: Duration);
+}
+
+/// The `embassy_time` clock, used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EmbassyClock;
+
+impl Clock for EmbassyClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn after(&self, duration: Duration) {
+        Timer::after(duration).await
+    }
+}
+
+impl<C: Clock> Clock for &C {
+    fn now(&self) -> Instant {
+        C::now(self)
+    }
+
+    async fn after(&self, duration: Duration) {
+        C::after(self, duration).await
+    }
+}
+
+/// Runs `fut` for at most `duration` of the given clock, returning `None` if it did not
+/// complete in time.
+pub(crate) async fn with_timeout<C: Clock, F: Future>(
+    clock: &C,
+    duration: Duration,
+    fut: F,
+) -> Option<F::Output> {
+    match select(fut, clock.after(duration)).await {
+        Either::First(output) => Some(output),
+        Either::Second(()) => None,
+    }
+}
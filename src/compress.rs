@@ -0,0 +1,206 @@
+//! Run-length compression of buffered frames.
+//!
+//! Compression is applied to the byte stream after defmt encoding, so the host must
+//! decompress the stream before passing it to the defmt decoder. The encoding is:
+//!
+//! - A byte other than [`ESCAPE`] stands for itself.
+//! - `ESCAPE, 0` stands for a single [`ESCAPE`] byte.
+//! - `ESCAPE, n, b` (with `n > 0`) stands for `n` repetitions of the byte `b`.
+//!
+//! Each run is encoded in full, so the stream can be decompressed without knowing where
+//! buffers or packets start. In Python:
+//!
+//! ```python
+//! def decompress(stream):
+//!     while (b := stream.read(1)) != b"":
+//!         if b != b"\xf7":
+//!             yield b
+//!         elif (n := stream.read(1)[0]) == 0:
+//!             yield b
+//!         else:
+//!             yield stream.read(1) * n
+//! ```
+
+use crate::buffer::BUFFERSIZE;
+
+/// Marker byte introducing an encoded run.
+pub const ESCAPE: u8 = 0xF7;
+
+/// Shortest run of a byte other than [`ESCAPE`] that is worth encoding.
+const MIN_RUN: usize = 4;
+
+/// Size of the scratch buffer that compressed packets are built in.
+///
+/// This holds a full-speed packet, or a 512-byte high-speed packet when the buffers are
+/// large enough for one, so that compression does not shorten high-speed packets.
+const SCRATCH_SIZE: usize = if BUFFERSIZE >= 512 { 512 } else { 64 };
+
+/// Encoder producing the compressed form of a byte slice.
+struct RleEncoder<'a> {
+    /// Remaining input.
+    input: &'a [u8],
+    /// Encoded bytes of the current run not yet produced.
+    pending: [u8; 3],
+    /// Number of bytes in `pending`.
+    pending_len: usize,
+    /// Index of the next byte of `pending` to produce.
+    pending_pos: usize,
+}
+
+impl<'a> RleEncoder<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pending: [0; 3],
+            pending_len: 0,
+            pending_pos: 0,
+        }
+    }
+}
+
+impl Iterator for RleEncoder<'_> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.pending_pos < self.pending_len {
+            self.pending_pos += 1;
+            return Some(self.pending[self.pending_pos - 1]);
+        }
+
+        let &byte = self.input.first()?;
+        let run = self
+            .input
+            .iter()
+            .take(u8::MAX as usize)
+            .take_while(|&&b| b == byte)
+            .count();
+
+        if byte != ESCAPE && run < MIN_RUN {
+            // Literal byte.
+            self.input = &self.input[1..];
+            return Some(byte);
+        }
+
+        self.input = &self.input[run..];
+        if byte == ESCAPE && run == 1 {
+            self.pending = [ESCAPE, 0, 0];
+            self.pending_len = 2;
+        } else {
+            self.pending = [ESCAPE, run as u8, byte];
+            self.pending_len = 3;
+        }
+        self.pending_pos = 1;
+        Some(ESCAPE)
+    }
+}
+
+/// Splits the compressed form of a byte slice into packets.
+pub(crate) struct Packets<'a> {
+    /// Encoder of the current input buffer.
+    encoder: RleEncoder<'a>,
+    /// Input buffers not yet started.
+    rest: &'a [&'a [u8]],
+    /// Maximum size of each packet.
+    packet_size: usize,
+    /// Buffer that the current packet is built in.
+    scratch: [u8; SCRATCH_SIZE],
+}
+
+impl<'a> Packets<'a> {
+    /// Compress the concatenation of `buffers` into packets of at most `packet_size`
+    /// bytes.
+    pub(crate) fn new(buffers: &'a [&'a [u8]], packet_size: usize) -> Self {
+        Self {
+            encoder: RleEncoder::new(&[]),
+            rest: buffers,
+            packet_size: packet_size.min(SCRATCH_SIZE),
+            scratch: [0; SCRATCH_SIZE],
+        }
+    }
+
+    /// Returns the next packet, or `None` once all the input has been compressed.
+    pub(crate) fn next_packet(&mut self) -> Option<&[u8]> {
+        let mut len = 0;
+        while len < self.packet_size {
+            let Some(byte) = self.next_byte() else {
+                break;
+            };
+            self.scratch[len] = byte;
+            len += 1;
+        }
+        (len > 0).then_some(&self.scratch[..len])
+    }
+
+    /// Returns the next compressed byte, moving on to the next input buffer as needed.
+    fn next_byte(&mut self) -> Option<u8> {
+        loop {
+            if let Some(byte) = self.encoder.next() {
+                return Some(byte);
+            }
+            let (&next, rest) = self.rest.split_first()?;
+            self.encoder = RleEncoder::new(next);
+            self.rest = rest;
+        }
+    }
+}
+
+#[cfg(all(test, not(feature = "disabled")))]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::testing::{host, MemoryTransport};
+
+    /// Returns the packets that `buffers` are compressed into.
+    fn compress(buffers: &[&[u8]], packet_size: usize) -> Vec<Vec<u8>> {
+        let mut packets = Packets::new(buffers, packet_size);
+        let mut sent = Vec::new();
+        while let Some(packet) = packets.next_packet() {
+            sent.push(packet.to_vec());
+        }
+        sent
+    }
+
+    #[test]
+    fn runs_are_encoded() {
+        let input = [vec![1, 2, 2, 2], vec![3; 4], vec![4; 300]].concat();
+        let packets = compress(&[&input], 64);
+        assert_eq!(
+            packets.concat(),
+            [1, 2, 2, 2, ESCAPE, 4, 3, ESCAPE, 255, 4, ESCAPE, 45, 4]
+        );
+        assert_eq!(host::decompress(&packets.concat()), input);
+    }
+
+    #[test]
+    fn escape_bytes_are_escaped() {
+        let input = [1, ESCAPE, 2, ESCAPE, ESCAPE];
+        let packets = compress(&[&input], 64);
+        assert_eq!(packets.concat(), [1, ESCAPE, 0, 2, ESCAPE, 2, ESCAPE]);
+        assert_eq!(host::decompress(&packets.concat()), input);
+    }
+
+    #[test]
+    fn runs_are_split_across_packets() {
+        let buffers = [vec![5; 10], vec![6, ESCAPE, 7], vec![5; 10]];
+        let buffers: Vec<&[u8]> = buffers.iter().map(Vec::as_slice).collect();
+        let packets = compress(&buffers, 2);
+        assert!(packets.iter().all(|packet| packet.len() <= 2));
+        assert_eq!(host::decompress(&packets.concat()), buffers.concat());
+    }
+
+    #[test]
+    fn flushed_frames_are_decompressed() {
+        let _lock = host::lock();
+        let frames = [vec![1; 20], vec![ESCAPE, 2, 3], vec![0; 30]];
+        for frame in &frames {
+            crate::write_raw_frame(frame);
+        }
+        let mut transport = MemoryTransport::new(64);
+        embassy_futures::block_on(crate::flush_all(&mut transport)).unwrap();
+        assert!(transport.bytes.len() < frames.concat().len());
+        assert_eq!(host::decode(&transport.bytes), frames.concat());
+    }
+}
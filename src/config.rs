@@ -0,0 +1,241 @@
+//! Builder for the USB device configuration used by the logger.
+
+use embassy_usb::Config;
+use static_cell::ConstStaticCell;
+
+/// Buffer holding the serial number formatted by
+/// [`LoggerConfig::serial_number_from_uid`].
+static SERIAL_NUMBER_BUF: ConstStaticCell<[u8; 2 * LoggerConfig::MAX_UID_LEN]> =
+    ConstStaticCell::new([0; 2 * LoggerConfig::MAX_UID_LEN]);
+
+/// USB device configuration for the logger.
+///
+/// This produces an [`embassy_usb::Config`] suitable for the logger's CDC ACM class,
+/// so that only the fields that identify the device need to be set.
+///
+/// ```ignore
+/// let config = LoggerConfig::default()
+///     .vid_pid(0x1234, 0x5678)
+///     .product("My Logger");
+///
+/// defmtusb::run(driver, size, config.into()).await;
+/// ```
+///
+/// The device class, subclass and protocol cannot be changed. They are always set
+/// for a composite device with interface association descriptors (IADs), which the
+/// CDC ACM class requires to be recognised by the host.
+#[derive(Clone, Copy, Debug)]
+pub struct LoggerConfig {
+    /// USB vendor ID.
+    vendor_id: u16,
+    /// USB product ID.
+    product_id: u16,
+    /// Manufacturer name string descriptor.
+    manufacturer: Option<&'static str>,
+    /// Product name string descriptor.
+    product: Option<&'static str>,
+    /// Serial number string descriptor.
+    serial_number: Option<&'static str>,
+    /// Maximum current drawn from the bus, in milliamps.
+    max_power: u16,
+    /// Maximum packet size of the control endpoint.
+    max_packet_size_0: u8,
+    /// Whether the device has its own power source.
+    self_powered: bool,
+}
+
+impl LoggerConfig {
+    /// The default configuration.
+    ///
+    /// This uses the testing VID and PID `0xC0DE:0xCAFE`, which must be replaced
+    /// for a device distributed to others.
+    pub const DEFAULT: Self = Self {
+        vendor_id: 0xC0DE,
+        product_id: 0xCAFE,
+        manufacturer: None,
+        product: None,
+        serial_number: None,
+        max_power: 100,
+        max_packet_size_0: 64,
+        self_powered: false,
+    };
+
+    /// Sets the USB vendor and product IDs.
+    pub const fn vid_pid(mut self, vid: u16, pid: u16) -> Self {
+        self.vendor_id = vid;
+        self.product_id = pid;
+        self
+    }
+
+    /// Sets the manufacturer name.
+    pub const fn manufacturer(mut self, manufacturer: &'static str) -> Self {
+        self.manufacturer = Some(manufacturer);
+        self
+    }
+
+    /// Sets the product name.
+    pub const fn product(mut self, product: &'static str) -> Self {
+        self.product = Some(product);
+        self
+    }
+
+    /// Sets the serial number.
+    pub const fn serial_number(mut self, serial_number: &'static str) -> Self {
+        self.serial_number = Some(serial_number);
+        self
+    }
+
+    /// Longest unique ID accepted by
+    /// [`serial_number_from_uid`](Self::serial_number_from_uid), in bytes.
+    pub const MAX_UID_LEN: usize = 16;
+
+    /// Sets the serial number to the chip's unique ID, formatted as upper-case hex.
+    ///
+    /// This gives each device its own serial number, so that the host can tell several
+    /// devices apart and keep their port names stable. The ID is usually read at boot,
+    /// for instance from the flash chip on the RP2040 or the UID registers on STM32:
+    ///
+    /// ```ignore
+    /// let uid = embassy_rp::otp::get_chipid().unwrap().to_be_bytes();
+    /// let config = LoggerConfig::default().serial_number_from_uid(&uid);
+    /// ```
+    ///
+    /// The string descriptors of [`Config`] must live for the rest of the program, so
+    /// the formatted serial number is kept in a static buffer managed by this crate,
+    /// which can only be used once. For other strings known only at runtime, such as
+    /// a product name, keep the string in a static of your own, for instance with
+    /// `static_cell`, and pass it to the other builder methods.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `uid` is longer than [`MAX_UID_LEN`](Self::MAX_UID_LEN) bytes, or if
+    /// called more than once.
+    pub fn serial_number_from_uid(self, uid: &[u8]) -> Self {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+        assert!(uid.len() <= Self::MAX_UID_LEN, "Unique ID too long");
+        let buf = SERIAL_NUMBER_BUF.take();
+        for (&byte, digits) in uid.iter().zip(buf.chunks_exact_mut(2)) {
+            digits[0] = HEX[usize::from(byte >> 4)];
+            digits[1] = HEX[usize::from(byte & 0x0F)];
+        }
+        let buf: &'static [u8] = buf;
+        // The buffer only holds ASCII hex digits, so this never fails.
+        let serial_number = core::str::from_utf8(&buf[..2 * uid.len()]).unwrap_or_default();
+        self.serial_number(serial_number)
+    }
+
+    /// Sets the maximum current drawn from the bus, in milliamps (default 100).
+    pub const fn max_power(mut self, max_power: u16) -> Self {
+        self.max_power = max_power;
+        self
+    }
+
+    /// Sets the maximum packet size of the control endpoint (default 64).
+    ///
+    /// This must be 8, 16, 32 or 64, and supported by the USB hardware.
+    pub const fn max_packet_size_0(mut self, size: u8) -> Self {
+        self.max_packet_size_0 = size;
+        self
+    }
+
+    /// Sets whether the device has its own power source (default `false`).
+    pub const fn self_powered(mut self, self_powered: bool) -> Self {
+        self.self_powered = self_powered;
+        self
+    }
+}
+
+impl Default for LoggerConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl From<LoggerConfig> for Config<'static> {
+    fn from(value: LoggerConfig) -> Self {
+        // `Config::new` sets the class codes for a composite device with IADs.
+        let mut config = Config::new(value.vendor_id, value.product_id);
+        config.composite_with_iads = true;
+        config.manufacturer = value.manufacturer;
+        config.product = value.product;
+        config.serial_number = value.serial_number;
+        config.max_power = value.max_power;
+        config.max_packet_size_0 = value.max_packet_size_0;
+        config.self_powered = value.self_powered;
+        config
+    }
+}
+
+/// Checks that the class codes of `config` let the host recognise the logger's CDC ACM
+/// class, before the USB builder is created.
+///
+/// With `composite_with_iads` set, the class and its two interfaces are described with
+/// interface association descriptors (IADs), which need the class codes of a composite
+/// device. embassy-usb also checks this, but with a single message for all three codes.
+/// Without IADs, the class codes are left to the application.
+///
+/// # Panics
+///
+/// Panics with a message naming the first field that is set wrongly and the value it
+/// requires.
+pub(crate) fn validate_config(config: &Config) {
+    if !config.composite_with_iads {
+        return;
+    }
+    // Class, subclass and protocol codes of a composite device with IADs.
+    let fields = [
+        ("device_class", config.device_class, 0xEF),
+        ("device_sub_class", config.device_sub_class, 0x02),
+        ("device_protocol", config.device_protocol, 0x01),
+    ];
+    for (field, value, required) in fields {
+        if value != required {
+            defmt::panic!(
+                "The USB `Config::{=str}` must be {=u8:#04x} with `composite_with_iads` set, \
+                 not {=u8:#04x}. `Config::new` and `LoggerConfig` set it correctly.",
+                field,
+                required,
+                value
+            );
+        }
+    }
+}
+
+/// Sizes of the logger's USB endpoint.
+///
+/// The `size` argument of [`run`](crate::run) is only the endpoint's maximum packet
+/// size, which is easily confused with the size of the log buffers. This names it
+/// explicitly, for [`run_with_sizes`](crate::run_with_sizes).
+///
+/// Only the endpoint's packet size can be set here. The log buffers are allocated
+/// statically, so their size and count are chosen with the `buffersize-*` and
+/// `buffercount-*` features, and the RAM they use is given by
+/// [`BUFFER_RAM`](crate::BUFFER_RAM).
+///
+/// ```ignore
+/// let sizes = Sizes {
+///     endpoint_packet: 64,
+/// };
+///
+/// defmtusb::run_with_sizes(driver, sizes, config).await;
+/// ```
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sizes {
+    /// Maximum packet size of the USB endpoint, which depends on the hardware: 8, 16,
+    /// 32 or 64 bytes at full speed, and 512 bytes at high speed. It must not be larger
+    /// than the log buffers.
+    pub endpoint_packet: u16,
+}
+
+impl Sizes {
+    /// The default sizes: 64-byte packets, as used by full-speed devices.
+    pub const DEFAULT: Self = Self {
+        endpoint_packet: 64,
+    };
+}
+
+impl Default for Sizes {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
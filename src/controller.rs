@@ -2,44 +2,455 @@
 
 use core::{cell::UnsafeCell, sync::atomic::Ordering};
 
-use portable_atomic::{AtomicBool, AtomicUsize};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+use portable_atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize};
 
-use crate::buffer::LogBuffer;
+use crate::buffer::{LogBuffer, BUFFERSIZE};
+
+/// The number of buffers used by the logger.
+#[cfg(not(any(feature = "buffercount-4", feature = "buffercount-8")))]
+pub(super) const BUFFERCOUNT: usize = 2;
+
+#[cfg(feature = "buffercount-4")]
+pub(super) const BUFFERCOUNT: usize = 4;
+
+#[cfg(feature = "buffercount-8")]
+pub(super) const BUFFERCOUNT: usize = 8;
+
+/// Number of bytes of RAM used by the buffers, the buffer size times the number of
+/// buffers.
+///
+/// This can be checked against a RAM budget at compile time:
+///
+/// ```
+/// const _: () = assert!(defmtusb::BUFFER_RAM <= 2048);
+/// ```
+pub const BUFFER_RAM: usize = BUFFERCOUNT * BUFFERSIZE;
+
+/// Returns the largest buffer size, among those of the `buffersize-*` features, with
+/// which `count` buffers fit in `budget` bytes, or `None` if none fit or `count` is 0.
+///
+/// The buffer size and count are chosen with features, so this helps pick them for a
+/// RAM budget. More buffers absorb more bursts while earlier ones are sent, while
+/// larger buffers send more data per transfer. It does not create the buffers: the
+/// logger has a single set of buffers, sized by the features, which can then be checked
+/// against the budget with [`BUFFER_RAM`].
+///
+/// ```
+/// assert_eq!(defmtusb::buffer_size_for_budget(2048, 4), Some(512));
+/// assert_eq!(defmtusb::buffer_size_for_budget(2048, 8), Some(256));
+/// assert_eq!(defmtusb::buffer_size_for_budget(2048, 0), None);
+/// assert_eq!(defmtusb::buffer_size_for_budget(usize::MAX, usize::MAX), None);
+/// ```
+pub const fn buffer_size_for_budget(budget: usize, count: usize) -> Option<usize> {
+    if count == 0 {
+        return None;
+    }
+    let mut size: usize = 1024;
+    while size >= 64 {
+        // A product that overflows is over any budget.
+        if let Some(ram) = size.checked_mul(count) {
+            if ram <= budget {
+                return Some(size);
+            }
+        }
+        size /= 2;
+    }
+    None
+}
+
+/// Value of `Controller::last_channel` when the current buffer holds no tagged frame.
+const NO_CHANNEL: u8 = u8::MAX;
+
+/// Longest time `wait_for_space` waits for a buffer in [`Mode::Lossless`].
+const LOSSLESS_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Bit of the `sending` mask set while the boot buffer is being sent.
+const SENDING_BOOT: usize = 1 << (usize::BITS - 1);
 
 /// The buffer controller of the logger.
-pub(super) static CONTROLLER: Controller = Controller::new();
+///
+/// With the `buffer-section` feature, it is placed in the `.defmtusb_buffers` linker
+/// section, so that the buffers can be put in a RAM region of the application's choice.
+///
+/// There is none with the `disabled` feature, so that the buffers take no RAM.
+#[cfg(not(feature = "disabled"))]
+#[cfg_attr(feature = "buffer-section", link_section = ".defmtusb_buffers")]
+pub(super) static CONTROLLER: DefaultController = Controller::new();
+
+/// Signalled when all buffers have been sent, or the controller is disabled.
+static DRAINED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signalled when the controller is enabled or disabled.
+static CONNECTION_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signalled by [`request_flush`] to wake the logger task.
+pub(super) static FLUSH_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Sends buffered frames without waiting for the next flush interval.
+///
+/// The active buffer is marked as flushing, even if it is only partially filled, and
+/// the logger task is woken to send it. This does nothing if there are no buffered
+/// frames.
+pub fn request_flush() {
+    // Buffers may also be waiting from before, so check even if nothing was swapped.
+    #[cfg(not(feature = "disabled"))]
+    if CONTROLLER.flush_current() || CONTROLLER.needs_flush() {
+        FLUSH_REQUESTED.signal(());
+    }
+}
+
+/// Waits until all buffered frames have been sent.
+///
+/// This completes once no buffer is waiting to be flushed and the active buffer is
+/// empty. Frames in a partially-filled active buffer are only sent once it fills, so
+/// call `defmt::flush()` first to include them.
+///
+/// Returns immediately if the logger is disabled (for instance because USB is
+/// disconnected), as buffered frames will not be sent. Only one task should wait at a
+/// time.
+pub async fn wait_for_drain() {
+    #[cfg(not(feature = "disabled"))]
+    loop {
+        if !CONTROLLER.is_enabled() || CONTROLLER.is_drained() {
+            return;
+        }
+        DRAINED.wait().await;
+    }
+}
+
+/// Returns `true` if the logger is enabled, which is the case while the USB device is
+/// connected and configured by the host.
+///
+/// This tracks whether the logger's endpoint is available, not whether a program on
+/// the host has opened the port (DTR), unless [`FlushConfig::require_dtr`] is set. It
+/// also reflects [`LoggerHandle::disable`]. This can be used to skip expensive logging
+/// when nobody is listening, or to drive a status LED.
+///
+/// [`FlushConfig::require_dtr`]: crate::FlushConfig::require_dtr
+/// [`LoggerHandle::disable`]: crate::LoggerHandle::disable
+pub fn is_connected() -> bool {
+    #[cfg(feature = "disabled")]
+    return false;
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.is_enabled()
+}
+
+/// Waits until the logger is connected, as reported by [`is_connected`].
+///
+/// Returns immediately if it is already connected. This lets an application task start
+/// work that is only useful while a host is listening, without polling. Only one task
+/// should wait for a change of connection at a time, with this or
+/// [`wait_disconnected`].
+///
+/// With the `disabled` feature, the logger never connects, so this never returns.
+pub async fn wait_connected() {
+    #[cfg(feature = "disabled")]
+    core::future::pending::<()>().await;
+    #[cfg(not(feature = "disabled"))]
+    while !CONTROLLER.is_enabled() {
+        CONNECTION_CHANGED.wait().await;
+    }
+}
+
+/// Waits until the logger is disconnected, as reported by [`is_connected`].
+///
+/// Returns immediately if it is already disconnected. Only one task should wait for a
+/// change of connection at a time, with this or [`wait_connected`].
+pub async fn wait_disconnected() {
+    #[cfg(not(feature = "disabled"))]
+    while CONTROLLER.is_enabled() {
+        CONNECTION_CHANGED.wait().await;
+    }
+}
+
+/// Returns the number of defmt frames started, that is the number of log calls made.
+///
+/// Together with [`dropped_frames`], this gives the proportion of frames delivered.
+/// The counter wraps on overflow.
+pub fn frames_started() -> u32 {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.frames_started.load(Ordering::Relaxed)
+}
+
+/// Returns the number of frames dropped because no buffer could accept them.
+///
+/// Frames are dropped whole, so the frames that were sent are never corrupted.
+/// Consistent drops indicate the buffers are too small for the volume of logging.
+pub fn dropped_frames() -> usize {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.dropped_frames.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes dropped because no buffer could accept them.
+pub fn dropped_bytes() -> usize {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.dropped_bytes.load(Ordering::Relaxed)
+}
+
+/// Returns the number of times buffered frames were lost because sending them failed.
+///
+/// Each failed transfer counts once, whether it failed because the host stopped
+/// reading (with [`write_timeout`](crate::FlushConfig::write_timeout)), the packet
+/// could not be sent, or the device was disconnected while sending. Unlike the
+/// statistics of the `metrics` feature, this is always counted. The counter wraps on
+/// overflow.
+pub fn flush_errors() -> usize {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.flush_errors.load(Ordering::Relaxed)
+}
+
+/// Returns the number of frames dropped since the last call, and resets the count.
+///
+/// Reading and resetting happen in one critical section, so no drop is missed or
+/// counted twice between periodic reports. This also resets the count used by the
+/// logger task's [`report_drops`](crate::FlushConfig::report_drops) warnings.
+pub fn take_dropped_frames() -> usize {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    critical_section::with(|_| {
+        let frames = CONTROLLER.dropped_frames.load(Ordering::Relaxed);
+        CONTROLLER.dropped_frames.store(0, Ordering::Relaxed);
+        frames
+    })
+}
+
+/// Returns the number of bytes dropped since the last call, and resets the count.
+///
+/// As [`take_dropped_frames`], this never misses or double-counts a drop.
+pub fn take_dropped_bytes() -> usize {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    critical_section::with(|_| {
+        let bytes = CONTROLLER.dropped_bytes.load(Ordering::Relaxed);
+        CONTROLLER.dropped_bytes.store(0, Ordering::Relaxed);
+        bytes
+    })
+}
+
+/// Returns the largest number of bytes held in a single buffer.
+///
+/// This can be used during development to choose the buffer size, and is reset with
+/// [`reset_peak_buffer_usage`].
+pub fn peak_buffer_usage() -> usize {
+    #[cfg(feature = "disabled")]
+    return 0;
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.peak_usage.load(Ordering::Relaxed)
+}
+
+/// Resets the value returned by [`peak_buffer_usage`], to measure the peak over a
+/// specific period.
+pub fn reset_peak_buffer_usage() {
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.peak_usage.store(0, Ordering::Relaxed);
+}
+
+/// Returns the number of bytes held across all buffers and the total buffer capacity,
+/// as `(used, capacity)`.
+///
+/// The buffers are inspected inside a critical section, so this never observes a
+/// partially-written frame, but the result may be out of date as soon as it returns.
+pub fn buffer_usage() -> (usize, usize) {
+    #[cfg(feature = "disabled")]
+    return (0, 0);
+    #[cfg(not(feature = "disabled"))]
+    CONTROLLER.usage()
+}
+
+/// What the logger does with a frame when the buffers are full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Mode {
+    /// Drop the frame, counting it in [`dropped_frames`]. Logging never waits.
+    #[default]
+    Drop,
+    /// Wait for the logger task to free a buffer before starting each frame.
+    ///
+    /// This stalls the logging code while the host is not reading, so it is mostly
+    /// useful in test setups where no frame may be lost. To avoid blocking forever
+    /// (for instance when logging from an interrupt that preempts the logger task),
+    /// the wait is abandoned after 100ms and the frame may then be dropped.
+    Lossless,
+}
+
+/// Which frames the logger drops when the buffers are full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the new frame, keeping the buffered frames.
+    #[default]
+    DropNewest,
+    /// Discard the oldest buffer waiting to be sent to make room for the new frame.
+    ///
+    /// This keeps the most recent frames, which are usually the most relevant when
+    /// investigating a crash. Each discarded buffer is counted as one dropped frame in
+    /// [`dropped_frames`]. A buffer that is being sent cannot be discarded, so the new
+    /// frame is still dropped in that case.
+    DropOldest,
+}
+
+/// What the logger does with a frame too long to fit in a buffer.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LongFramePolicy {
+    /// Drop the frame, counting it in [`dropped_frames`], so that every frame sent is
+    /// whole.
+    #[default]
+    Drop,
+    /// Keep as much of the frame as fits in a buffer, followed by
+    /// [`TRUNCATION_MARKER`], and drop the rest.
+    ///
+    /// The start of a frame holds its format string index, which identifies its level
+    /// and location, so this keeps the most useful part of an over-long frame. The
+    /// truncated frame cannot be decoded by a standard defmt decoder: the host must
+    /// recognise the marker, and decode or report the partial frame before it itself.
+    /// The bytes dropped are counted in [`dropped_bytes`], but the frame is not counted
+    /// in [`dropped_frames`]. Frames that would fit in a buffer, but not in the free
+    /// space, are still dropped whole.
+    Truncate,
+}
+
+/// Marker written after a truncated frame, with [`LongFramePolicy::Truncate`].
+///
+/// The leading `0x00` ends the partial frame, and the trailing `0x00` delimits the
+/// marker like a defmt frame. The marker contains no other `0x00` byte, so it does not
+/// disturb the decoding of the frames around it.
+pub const TRUNCATION_MARKER: [u8; 4] = [0x00, b'T', b'R', 0x00];
+
+/// What the logger does with frames logged before the host first attaches.
+///
+/// Until the logger task first connects to the host, frames can only be buffered, and
+/// the buffers may fill during a long boot.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StartupPolicy {
+    /// Buffer frames, following the [`OverflowPolicy`] when the buffers are full. With
+    /// the default [`OverflowPolicy::DropNewest`], this keeps the earliest frames.
+    #[default]
+    Buffer,
+    /// Buffer frames, discarding the oldest buffer when full as with
+    /// [`OverflowPolicy::DropOldest`], so that the frames logged just before the host
+    /// attaches are kept. The [`OverflowPolicy`] applies once it has attached.
+    KeepLatest,
+    /// Disable the logger until the host attaches, so that frames are ignored without
+    /// being buffered. Any frames already buffered are discarded.
+    ///
+    /// The host attaches once the device is enumerated, or, with
+    /// [`FlushConfig::require_dtr`](crate::FlushConfig::require_dtr), once a program
+    /// opens the port, so together they drop frames until a reader is present.
+    Discard,
+}
+
+/// The controller sized by the `buffercount-*` and `buffersize-*` features, as used by
+/// the logger.
+pub(super) type DefaultController = Controller<BUFFERCOUNT, BUFFERSIZE>;
 
 /// Controller of the buffers of the logger.
-pub struct Controller {
+///
+/// The controller cycles through `N` buffers of `SIZE` bytes each, writing to one while
+/// the others are waiting to be flushed. Both default to the sizes selected by the
+/// features.
+///
+/// The logger has a single controller, [`CONTROLLER`], as the `defmt` global logger
+/// is a single instance for the whole program. Its sizes are therefore still chosen
+/// with the features rather than by the application, and the type is not exported:
+/// the parameters let other sizes be used in tests.
+pub struct Controller<const N: usize = BUFFERCOUNT, const SIZE: usize = BUFFERSIZE> {
     /// Index of the currently active buffer.
     current_idx: AtomicUsize,
     /// The controller is enabled.
     enabled: AtomicBool,
-    /// Alternating buffers holding defmt frames.
+    /// Bitmask of the buffers being sent by `flush`, with bit `i` set for buffer `i`,
+    /// and `SENDING_BOOT` set for the boot buffer.
+    sending: AtomicUsize,
+    /// Number of frames started.
+    frames_started: AtomicU32,
+    /// Number of frames dropped because no buffer accepted them.
+    dropped_frames: AtomicUsize,
+    /// Number of bytes dropped because no buffer accepted them.
+    dropped_bytes: AtomicUsize,
+    /// Number of transfers that failed, losing the buffers being sent.
+    flush_errors: AtomicUsize,
+    /// Largest number of bytes held in a single buffer.
+    peak_usage: AtomicUsize,
+    /// Wait for a free buffer before each frame, see [`Mode::Lossless`].
+    lossless: AtomicBool,
+    /// Discard the oldest buffer when full, see [`OverflowPolicy::DropOldest`].
+    drop_oldest: AtomicBool,
+    /// Truncate frames too long for a buffer, see [`LongFramePolicy::Truncate`].
+    truncate_long: AtomicBool,
+    /// Discard the oldest buffer when full until attached, see
+    /// [`StartupPolicy::KeepLatest`].
+    keep_latest: AtomicBool,
+    /// The logger task has connected to the host at least once.
+    attached: AtomicBool,
+    /// Wake the logger task when a buffer is marked as flushing, see
+    /// [`FlushConfig::flush_when_ready`](crate::FlushConfig::flush_when_ready).
+    wake_on_swap: AtomicBool,
+    /// Number of bytes at which a buffer is marked as flushing, at most `SIZE`.
+    watermark: AtomicUsize,
+    /// Cursor in the current buffer at which the frame being written starts.
+    frame_start: AtomicUsize,
+    /// The frame being written did not fit, and its remaining bytes are ignored.
+    frame_dropped: AtomicBool,
+    /// Sequence number of the next buffer sent, with the `sequence-numbers` feature.
+    #[cfg_attr(not(feature = "sequence-numbers"), allow(dead_code))]
+    sequence: AtomicU32,
+    /// Channel of the last frame in the current buffer, with the `channels` feature.
+    #[cfg_attr(not(feature = "channels"), allow(dead_code))]
+    last_channel: AtomicU8,
+    /// Rotating buffers holding defmt frames.
     //
     // SAFETY: These are OK to be unsynchronised UnsafeCells because they are only written to from
     // within a critical section, and taken out of use by that critical section (marked as
     // flushing). They are only put back into use by the asynchronous logger task outside of the
     // critical sections where writing occurs.
-    buffers: [UnsafeCell<LogBuffer>; 2],
+    buffers: [UnsafeCell<LogBuffer<SIZE>>; N],
 }
 
 // Sync is required for types in static variables.
 //
 // SAFETY: This is safe to implement because mutation of the LogBuffers only occurs within a
-// critical section, preventing concurrent modification.
-unsafe impl Sync for Controller {}
+// critical section, preventing concurrent modification. On multi-core targets, the
+// `critical-section` implementation must also exclude the other cores.
+unsafe impl<const N: usize, const SIZE: usize> Sync for Controller<N, SIZE> {}
 
-impl Controller {
+impl<const N: usize, const SIZE: usize> Controller<N, SIZE> {
     /// Static initializer.
     pub const fn new() -> Self {
+        // `write` moves frames between two distinct buffers, and each buffer needs a bit
+        // in the `sending` mask.
+        assert!(N >= 2, "Too few buffers");
+        assert!(N <= usize::BITS as usize, "Too many buffers");
         Self {
             current_idx: AtomicUsize::new(0),
             enabled: AtomicBool::new(true),
-            buffers: [
-                UnsafeCell::new(LogBuffer::new()),
-                UnsafeCell::new(LogBuffer::new()),
-            ],
+            sending: AtomicUsize::new(0),
+            frames_started: AtomicU32::new(0),
+            dropped_frames: AtomicUsize::new(0),
+            dropped_bytes: AtomicUsize::new(0),
+            flush_errors: AtomicUsize::new(0),
+            peak_usage: AtomicUsize::new(0),
+            lossless: AtomicBool::new(false),
+            drop_oldest: AtomicBool::new(false),
+            truncate_long: AtomicBool::new(false),
+            keep_latest: AtomicBool::new(false),
+            attached: AtomicBool::new(false),
+            wake_on_swap: AtomicBool::new(false),
+            watermark: AtomicUsize::new(SIZE),
+            frame_start: AtomicUsize::new(0),
+            frame_dropped: AtomicBool::new(false),
+            sequence: AtomicU32::new(0),
+            last_channel: AtomicU8::new(NO_CHANNEL),
+            buffers: [const { UnsafeCell::new(LogBuffer::new()) }; N],
         }
     }
 
@@ -47,6 +458,81 @@ impl Controller {
     #[inline]
     pub(super) fn enable(&self) {
         self.enabled.store(true, Ordering::Relaxed);
+        CONNECTION_CHANGED.signal(());
+    }
+
+    /// Returns `true` if the controller is enabled.
+    #[inline]
+    pub(super) fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Sets what happens to frames when the buffers are full.
+    #[inline]
+    pub(super) fn set_mode(&self, mode: Mode) {
+        self.lossless
+            .store(mode == Mode::Lossless, Ordering::Relaxed);
+    }
+
+    /// Sets which frames are dropped when the buffers are full.
+    #[inline]
+    pub(super) fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        self.drop_oldest
+            .store(policy == OverflowPolicy::DropOldest, Ordering::Relaxed);
+    }
+
+    /// Sets what happens to frames too long to fit in a buffer.
+    #[inline]
+    pub(super) fn set_long_frame_policy(&self, policy: LongFramePolicy) {
+        self.truncate_long
+            .store(policy == LongFramePolicy::Truncate, Ordering::Relaxed);
+    }
+
+    /// Sets whether the logger task is woken whenever a buffer is marked as flushing.
+    pub(super) fn set_wake_on_swap(&self, wake: bool) {
+        self.wake_on_swap.store(wake, Ordering::Relaxed);
+    }
+
+    /// Sets what happens to frames logged before the host first attaches.
+    ///
+    /// This has no effect once the host has attached.
+    pub(super) fn set_startup_policy(&self, policy: StartupPolicy) {
+        critical_section::with(|_| {
+            if self.attached.load(Ordering::Relaxed) {
+                return;
+            }
+            self.keep_latest
+                .store(policy == StartupPolicy::KeepLatest, Ordering::Relaxed);
+            if policy == StartupPolicy::Discard {
+                self.disable();
+            }
+        });
+    }
+
+    /// Records that the logger task has connected to the host, and enables the
+    /// controller.
+    #[inline]
+    pub(super) fn attach(&self) {
+        self.attached.store(true, Ordering::Relaxed);
+        // Send the frames logged during boot first, and buffer the rest as usual.
+        crate::boot::close();
+        self.enable();
+    }
+
+    /// Returns `true` if the oldest buffer should be discarded when the buffers are full.
+    #[inline]
+    fn drops_oldest(&self) -> bool {
+        self.drop_oldest.load(Ordering::Relaxed)
+            || (self.keep_latest.load(Ordering::Relaxed) && !self.attached.load(Ordering::Relaxed))
+    }
+
+    /// Sets the number of bytes at which the current buffer is marked as flushing.
+    ///
+    /// Values larger than the buffer size are clamped to it, which is the default and
+    /// only flushes buffers once they are full.
+    #[inline]
+    pub(super) fn set_watermark(&self, bytes: usize) {
+        self.watermark.store(bytes.min(SIZE), Ordering::Relaxed);
     }
 
     /// Disables the controller.
@@ -54,32 +540,68 @@ impl Controller {
     /// A disabled controller silently ignores any defmt logging.
     ///
     /// The internal buffers are reset when the controller is disabled to prevent any
-    /// partial frames being transmitted when the controller is re-enabled.
+    /// partial frames being transmitted when the controller is re-enabled. Buffers that
+    /// are in the middle of being sent are left for `flush` to reset once it is done.
     #[inline]
     pub(super) fn disable(&self) {
         self.enabled.store(false, Ordering::Relaxed);
-        let first = self.buffers[0].get();
-        let second = self.buffers[1].get();
         critical_section::with(|_| {
-            // SAFETY: We are in a critical section, and this function is only called on
-            // EndpointError::Disabled when flushing a buffer. It cannot disturb any ongoing defmt
-            // writes because they take their own critical section, and the controller is already
-            // marked as disabled so any new defmt writes (or flushes) will be ignored.
-            unsafe { &mut *first }.reset();
-            unsafe { &mut *second }.reset();
+            let sending = self.sending.load(Ordering::Relaxed);
+            for (idx, cell) in self.buffers.iter().enumerate() {
+                if sending & (1 << idx) != 0 {
+                    continue;
+                }
+                // SAFETY: We are in a critical section. It cannot disturb any ongoing defmt
+                // writes because they take their own critical section, and the controller is
+                // already marked as disabled so any new defmt writes (or flushes) will be
+                // ignored.
+                unsafe { &mut *cell.get() }.reset();
+            }
+        });
+        crate::boot::discard();
+        // Wake any task waiting for the buffers to drain, as they will not be sent.
+        DRAINED.signal(());
+        CONNECTION_CHANGED.signal(());
+    }
+
+    /// Discard the buffered frames, leaving the controller enabled.
+    ///
+    /// As with `disable`, buffers that are in the middle of being sent are left for
+    /// `flush` to reset once it is done. This is done in a critical section, so it
+    /// cannot run in the middle of a defmt frame.
+    pub(super) fn clear(&self) {
+        critical_section::with(|_| {
+            let sending = self.sending.load(Ordering::Relaxed);
+            for (idx, cell) in self.buffers.iter().enumerate() {
+                if sending & (1 << idx) != 0 {
+                    continue;
+                }
+                // SAFETY: We are in a critical section, so no defmt write is in progress,
+                // and the buffer is not being sent.
+                unsafe { &mut *cell.get() }.reset();
+            }
+            // Frames logged from now on start a fresh buffer.
+            self.frame_start.store(0, Ordering::Relaxed);
+            self.last_channel.store(NO_CHANNEL, Ordering::Relaxed);
         });
+        crate::boot::discard();
+        if self.is_drained() {
+            DRAINED.signal(());
+        }
     }
 
-    /// Mark the current buffer as flushing and set the other to be active.
+    /// Mark the current buffer as flushing and set the next to be active.
+    ///
+    /// Returns `false` without doing anything if the controller is disabled.
     ///
     /// # Safety
     ///
     /// Callers must ensure they are inside a critical section and there are no conflicting updates
     /// made to the buffer index or the current buffer's state enum.
-    pub(super) unsafe fn swap(&self) {
+    pub(super) unsafe fn swap(&self) -> bool {
         // Do nothing if not enabled.
         if !self.enabled.load(Ordering::Relaxed) {
-            return;
+            return false;
         }
 
         let current_idx = self.current_idx.load(Ordering::Relaxed);
@@ -93,17 +615,225 @@ impl Controller {
             current.flush();
         }
 
-        // 'Swap' the buffers by xor-ing the current index with 1.
+        // 'Swap' the buffers by moving on to the next index.
         // This is the only place where current_idx is changed.
-        self.current_idx.store(current_idx ^ 1, Ordering::Relaxed);
+        self.current_idx
+            .store((current_idx + 1) % N, Ordering::Relaxed);
+        // Any frame being written continues from the start of the next buffer, which
+        // holds no tagged frame yet.
+        self.frame_start.store(0, Ordering::Relaxed);
+        self.last_channel.store(NO_CHANNEL, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        crate::stats::COUNTERS.record_swap();
+        if self.wake_on_swap.load(Ordering::Relaxed) {
+            FLUSH_REQUESTED.signal(());
+        }
+        true
+    }
+
+    /// Mark the current buffer as flushing if it holds any data.
+    ///
+    /// Returns `true` if the buffer was marked as flushing.
+    pub(super) fn flush_current(&self) -> bool {
+        critical_section::with(|_| {
+            let current_idx = self.current_idx.load(Ordering::Relaxed);
+            // SAFETY: We are in a critical section, so no defmt write is in progress, and
+            // the buffer is only read.
+            let current = unsafe { &*self.buffers[current_idx].get() };
+            // SAFETY: We are in a critical section.
+            current.writable() && current.cursor > 0 && unsafe { self.swap() }
+        })
+    }
+
+    /// Returns the number of bytes held across all buffers and the total capacity.
+    fn usage(&self) -> (usize, usize) {
+        let used = critical_section::with(|_| {
+            self.buffers
+                .iter()
+                // SAFETY: We are in a critical section, so no defmt write is in progress,
+                // and the buffers are only read.
+                .map(|cell| unsafe { &*cell.get() }.cursor)
+                .sum()
+        });
+        (used, N * LogBuffer::<SIZE>::CAPACITY)
+    }
+
+    /// Mark the current buffer as flushing if it has held data for at least `max_age`,
+    /// as measured at time `now`.
+    ///
+    /// This lets partially-filled buffers be sent to the host, instead of waiting for
+    /// them to fill. An empty buffer is never marked as flushing.
+    ///
+    /// Returns `true` if the buffer was marked as flushing.
+    pub(super) fn flush_stale(&self, max_age: Duration, now: Instant) -> bool {
+        critical_section::with(|_| {
+            let current_idx = self.current_idx.load(Ordering::Relaxed);
+            // SAFETY: We are in a critical section, so no defmt write is in progress, and
+            // only the buffer's age is changed.
+            let current = unsafe { &mut *self.buffers[current_idx].get() };
+            // SAFETY: We are in a critical section.
+            current.writable() && current.is_stale(max_age, now) && unsafe { self.swap() }
+        })
+    }
+
+    /// Start a defmt frame in the current buffer.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from within the defmt critical section, before the frame's bytes
+    /// are written.
+    #[inline]
+    pub(super) unsafe fn start_frame(&self) {
+        let started = self.frames_started.load(Ordering::Relaxed);
+        self.frames_started
+            .store(started.wrapping_add(1), Ordering::Relaxed);
+
+        let current_idx = self.current_idx.load(Ordering::Relaxed);
+        // SAFETY: We are in a critical section, and the buffer is only read.
+        let current = unsafe { &*self.buffers[current_idx].get() };
+        self.frame_start.store(current.cursor, Ordering::Relaxed);
+        self.frame_dropped.store(false, Ordering::Relaxed);
+        // SAFETY: We are in the critical section required by this function.
+        unsafe { crate::boot::start_frame() };
+
+        // Tag the frame if its channel differs from the previous frame in the buffer.
+        // The tag is written as part of the frame, so that it moves with the frame.
+        #[cfg(feature = "channels")]
+        {
+            let channel = crate::channel::current();
+            if channel != self.last_channel.load(Ordering::Relaxed) {
+                // SAFETY: We are in the critical section required by this function.
+                unsafe { self.write(&crate::channel::tag(channel)) };
+            }
+        }
+    }
+
+    /// Finish the defmt frame being written.
+    ///
+    /// The current buffer is marked as flushing if it has reached the watermark set by
+    /// `set_watermark`, or is nearly full. This is done between frames so that a frame
+    /// is never split across buffers.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from within the defmt critical section, after the frame's bytes
+    /// are written.
+    #[inline]
+    pub(super) unsafe fn end_frame(&self) {
+        #[cfg(feature = "channels")]
+        if !self.frame_dropped.load(Ordering::Relaxed) {
+            self.last_channel
+                .store(crate::channel::current(), Ordering::Relaxed);
+        }
+        // A frame that was kept is all in the boot buffer while it is open, and otherwise
+        // all in the current buffer, from `frame_start`. Its length is taken from the
+        // cursors, so that the bytes written are counted once per frame rather than on
+        // every write.
+        #[cfg(feature = "metrics")]
+        if self.is_enabled() && !self.frame_dropped.load(Ordering::Relaxed) {
+            // SAFETY: We are in the critical section required by this function.
+            let len = unsafe { crate::boot::frame_len() }.unwrap_or_else(|| {
+                let current_idx = self.current_idx.load(Ordering::Relaxed);
+                // SAFETY: We are in a critical section, and the buffer is only read.
+                let current = unsafe { &*self.buffers[current_idx].get() };
+                let start = self.frame_start.load(Ordering::Relaxed);
+                current.cursor.saturating_sub(start)
+            });
+            crate::stats::COUNTERS.record_write(len);
+            crate::stats::COUNTERS.record_frame(len);
+        }
+        self.frame_dropped.store(false, Ordering::Relaxed);
+
+        let current_idx = self.current_idx.load(Ordering::Relaxed);
+        // SAFETY: We are in a critical section, and the buffer is only read.
+        let current = unsafe { &*self.buffers[current_idx].get() };
+
+        // Buffers have always been flushed once within two bytes of full.
+        //
+        // TODO: Is this necessary? Why -2? Is it to avoid the max-packet-size transfer
+        // needing a shorter transfer afterwards?
+        let threshold = self.watermark.load(Ordering::Relaxed).min(SIZE - 2);
+        if current.writable() && current.cursor > 0 && current.cursor >= threshold {
+            // SAFETY: We are in the critical section required by this function.
+            unsafe { self.swap() };
+        }
+
+        // SAFETY: As above.
+        unsafe { self.debug_check() };
+    }
+
+    /// Check the invariants the controller relies on, in debug builds only.
+    ///
+    /// - No buffer holds more than `SIZE - 1` bytes (see `LogBuffer::accepts`).
+    /// - Every buffer being sent is marked as flushing, so it is never written to.
+    /// - The frame being written starts within the current buffer.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from within a critical section.
+    #[inline]
+    unsafe fn debug_check(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
+        let sending = self.sending.load(Ordering::Relaxed);
+        for (idx, cell) in self.buffers.iter().enumerate() {
+            // SAFETY: We are in a critical section, and the buffers are only read.
+            let buffer = unsafe { &*cell.get() };
+            debug_assert!(buffer.cursor < SIZE, "buffer {} overflowed", idx);
+            debug_assert!(
+                sending & (1 << idx) == 0 || buffer.is_flushing(),
+                "buffer {} is being sent but writable",
+                idx
+            );
+        }
+        let current_idx = self.current_idx.load(Ordering::Relaxed);
+        // SAFETY: As above.
+        let current = unsafe { &*self.buffers[current_idx].get() };
+        debug_assert!(
+            !current.writable() || self.frame_start.load(Ordering::Relaxed) <= current.cursor,
+            "frame starts past the end of the current buffer"
+        );
+    }
+
+    /// Abandon the frame being written, removing the part of it already in the current
+    /// buffer.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from within a critical section, instead of `end_frame`.
+    pub(super) unsafe fn abort_frame(&self) {
+        let current_idx = self.current_idx.load(Ordering::Relaxed);
+        // SAFETY: We are in a critical section, guaranteed by the caller.
+        let current = unsafe { &mut *self.buffers[current_idx].get() };
+        if current.writable() {
+            current.truncate(self.frame_start.load(Ordering::Relaxed));
+        }
+        self.frame_dropped.store(false, Ordering::Relaxed);
     }
 
     /// Write defmt-encoded bytes to the current buffer.
     ///
+    /// Frames are kept whole: if the current buffer cannot accept the bytes, the part of
+    /// the frame already written is moved to the next buffer along with the bytes, and
+    /// the current buffer is marked as flushing. If the next buffer cannot accept the
+    /// whole frame either (because it has not yet been flushed, or the frame is larger
+    /// than a buffer), the frame is dropped: the part already written is removed, the
+    /// rest of the frame is ignored, and the drop is counted in [`dropped_frames`] and
+    /// [`dropped_bytes`].
+    ///
+    /// This runs inside the defmt critical section, so it is kept bounded: it copies at
+    /// most one buffer's worth of bytes and otherwise only performs a fixed number of
+    /// relaxed atomic loads and stores. It never waits, and never calls into the clock or
+    /// the USB stack. Moving to the next buffer (see `swap`) is a store to the buffer's
+    /// state and a store to the buffer index, and with
+    /// [`FlushConfig::flush_when_ready`](crate::FlushConfig::flush_when_ready) a signal
+    /// waking the logger task.
+    ///
     /// # Safety
     ///
     /// This writes to the underlying buffers, so the caller must ensure they are
-    /// inside a critical section.
+    /// inside a critical section, between calls to `start_frame` and `end_frame`.
     #[inline]
     pub(super) unsafe fn write(&self, bytes: &[u8]) {
         // Do nothing if not enabled.
@@ -111,47 +841,293 @@ impl Controller {
             return;
         }
 
+        // Keep every frame in the history, even if it is dropped below.
+        // SAFETY: We are in a critical section, as guaranteed by the caller.
+        #[cfg(feature = "history")]
+        unsafe {
+            crate::history::record(bytes)
+        };
+        // SAFETY: As above.
+        #[cfg(feature = "semihosting-mirror")]
+        unsafe {
+            crate::semihosting::mirror(bytes)
+        };
+
+        // Frames logged during boot go to the boot buffer while it is open.
+        //
+        // SAFETY: We are in the critical section required by this function.
+        match unsafe { crate::boot::write(bytes) } {
+            crate::boot::Write::Written => return,
+            crate::boot::Write::Closed => (),
+            // SAFETY: As above.
+            crate::boot::Write::Full(frame) => unsafe { self.write_buffers(frame) },
+        }
+
+        // SAFETY: As above.
+        unsafe { self.write_buffers(bytes) };
+    }
+
+    /// Write defmt-encoded bytes to the rotating buffers, as described in `write`.
+    ///
+    /// # Safety
+    ///
+    /// As `write`.
+    #[inline]
+    unsafe fn write_buffers(&self, bytes: &[u8]) {
+        // Ignore the rest of a dropped frame.
+        if self.frame_dropped.load(Ordering::Relaxed) {
+            self.record_dropped_bytes(bytes.len());
+            return;
+        }
+
         let current_idx = self.current_idx.load(Ordering::Relaxed);
-        let other_idx = current_idx ^ 1;
+        let other_idx = (current_idx + 1) % N;
 
         // SAFETY: This function is only called while a critical section is held by the defmt
         // logger, so we are OK to mutate the buffers. This is also the only place where the
-        // buffers' underlying store is changed.
+        // buffers' underlying store is changed. The two indices differ as `N` is at least 2.
         let current = unsafe { &mut *(self.buffers[current_idx].get()) };
         let other = unsafe { &mut *(self.buffers[other_idx].get()) };
         // If the current buffer accepts the necessary bytes, write to it.
         if current.accepts(bytes.len()) {
             // Write to the buffer the data.
-            current.write(bytes);
+            let written = current.write(bytes);
+            debug_assert_eq!(written, bytes.len(), "accepted bytes were not all written");
+            self.record_usage(current.cursor);
+            return;
+        }
+
+        // The part of the frame already written to the current buffer. A buffer that is
+        // not writable (because it is still being sent) holds none of the frame.
+        let frame = if current.writable() {
+            self.frame_start.load(Ordering::Relaxed).min(current.cursor)..current.cursor
         } else {
-            // If it doesn't accept the bytes, mark it as flushing and swap buffers.
-            // TODO: What if the alternate buffer _does not_ accept the bytes?
-            // TODO: Document safety of this.
-            self.swap();
-
-            if other.accepts(bytes.len()) {
-                // Write to the buffer the data.
-                other.write(bytes);
+            0..0
+        };
+
+        // With `OverflowPolicy::DropOldest` (or `StartupPolicy::KeepLatest` before the
+        // host attaches), discard the next buffer if it is still waiting to be sent, as it
+        // holds the oldest frames. A frame too long for an empty buffer would be dropped
+        // anyway, so the buffer is kept for it. A buffer holds at most `SIZE - 1` bytes,
+        // see `LogBuffer::accepts`.
+        if self.drops_oldest()
+            && frame.len() + bytes.len() < SIZE
+            && other.is_flushing()
+            && self.sending.load(Ordering::Relaxed) & (1 << other_idx) == 0
+        {
+            self.record_drop(other.cursor);
+            other.reset();
+        }
+
+        if other.accepts(frame.len() + bytes.len()) {
+            // Move the frame to the next buffer and carry on writing there.
+            let start = other.cursor;
+            let written = other.write(&current.data[frame.clone()]) + other.write(bytes);
+            debug_assert_eq!(
+                written,
+                frame.len() + bytes.len(),
+                "accepted bytes were not all written"
+            );
+            if current.writable() {
+                current.truncate(frame.start);
+            }
+            // SAFETY: We are in the critical section required by this function, and this is
+            // the only place within it that changes the buffer index.
+            unsafe { self.swap() };
+            self.frame_start.store(start, Ordering::Relaxed);
+            self.record_usage(other.cursor);
+        } else if self.truncate_long.load(Ordering::Relaxed)
+            && frame.len() + bytes.len() >= SIZE
+            && self.truncate_frame(current, other, frame.clone(), bytes)
+        {
+            // The frame is too long for any buffer, and was truncated.
+        } else {
+            // Drop the whole frame, and send what the current buffer held before it.
+            if current.writable() {
+                current.truncate(frame.start);
+                if current.cursor > 0 {
+                    // SAFETY: We are in the critical section required by this function.
+                    unsafe { self.swap() };
+                }
+            }
+            self.frame_dropped.store(true, Ordering::Relaxed);
+            self.record_drop(frame.len() + bytes.len());
+            // Tell apart a host that is too slow from a frame that would never fit.
+            #[cfg(feature = "metrics")]
+            if frame.len() + bytes.len() >= SIZE {
+                crate::stats::COUNTERS.record_oversized();
+            } else {
+                crate::stats::COUNTERS.record_stall();
             }
         }
     }
 
+    /// Write the start of a frame too long for any buffer, followed by
+    /// [`TRUNCATION_MARKER`], and ignore the rest of the frame.
+    ///
+    /// `frame` is the part of the frame already written to `current`, and `bytes` the
+    /// bytes being written. The frame is moved to `other` if it is empty, to keep as much
+    /// of it as possible, and otherwise truncated in place.
+    ///
+    /// Returns `false` without changing anything if no buffer has room for the marker
+    /// after the start of the frame.
+    ///
+    /// # Safety
+    ///
+    /// Must be called from within `write`, with the buffers it holds.
+    unsafe fn truncate_frame(
+        &self,
+        current: &mut LogBuffer<SIZE>,
+        other: &mut LogBuffer<SIZE>,
+        frame: core::ops::Range<usize>,
+        bytes: &[u8],
+    ) -> bool {
+        // A buffer holds at most `SIZE - 1` bytes, see `LogBuffer::accepts`.
+        let limit = SIZE - 1 - TRUNCATION_MARKER.len();
+        let kept =
+            if other.writable() && other.cursor == 0 && (frame.start > 0 || !current.writable()) {
+                // Move the start of the frame to the empty next buffer.
+                let moved = frame.len().min(limit);
+                other.write(&current.data[frame.start..frame.start + moved]);
+                if current.writable() {
+                    current.truncate(frame.start);
+                }
+                // SAFETY: We are in the critical section required by `write`.
+                unsafe { self.swap() };
+                self.frame_start.store(0, Ordering::Relaxed);
+                let written = other.write(&bytes[..bytes.len().min(limit - moved)]);
+                other.write(&TRUNCATION_MARKER);
+                self.record_usage(other.cursor);
+                moved + written
+            } else if current.writable() && frame.start < limit {
+                // Truncate the frame in place.
+                current.truncate(limit);
+                let kept = current.cursor - frame.start;
+                let written = current.write(&bytes[..bytes.len().min(limit - current.cursor)]);
+                current.write(&TRUNCATION_MARKER);
+                self.record_usage(current.cursor);
+                kept + written
+            } else {
+                return false;
+            };
+        self.frame_dropped.store(true, Ordering::Relaxed);
+        self.record_dropped_bytes(frame.len() + bytes.len() - kept);
+        true
+    }
+
+    /// Record a write leaving `cursor` bytes held in the buffer.
+    ///
+    /// The bytes written are counted for the metrics once per frame, by `end_frame`.
+    /// Like `record_drop`, this is only called from within the critical section.
+    #[inline]
+    fn record_usage(&self, cursor: usize) {
+        if cursor > self.peak_usage.load(Ordering::Relaxed) {
+            self.peak_usage.store(cursor, Ordering::Relaxed);
+        }
+    }
+
+    /// Record that a frame was dropped, along with the given number of its bytes.
+    ///
+    /// This is only called from within the critical section held by `write`, so plain
+    /// loads and stores are used rather than read-modify-write operations, which some
+    /// targets can only provide by taking another critical section.
+    #[inline]
+    fn record_drop(&self, len: usize) {
+        let frames = self.dropped_frames.load(Ordering::Relaxed);
+        self.dropped_frames.store(frames + 1, Ordering::Relaxed);
+        self.record_dropped_bytes(len);
+        #[cfg(feature = "metrics")]
+        crate::stats::COUNTERS.record_drop();
+    }
+
+    /// Record that the given number of bytes of an already dropped frame were dropped.
+    #[inline]
+    fn record_dropped_bytes(&self, len: usize) {
+        let bytes = self.dropped_bytes.load(Ordering::Relaxed);
+        self.dropped_bytes.store(bytes + len, Ordering::Relaxed);
+    }
+
+    /// In [`Mode::Lossless`], waits until a whole frame can be written without dropping.
+    ///
+    /// That is the case when the buffer after the current one is empty, as a frame that
+    /// does not fit in the rest of the current buffer is moved on to it. The wait is
+    /// abandoned after `LOSSLESS_TIMEOUT`, or if the controller is disabled.
+    ///
+    /// This must be called outside of a critical section, so that the logger task can
+    /// run and free a buffer.
+    pub(super) fn wait_for_space(&self) {
+        if !self.lossless.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let deadline = Instant::now() + LOSSLESS_TIMEOUT;
+        while self.is_enabled() && !self.has_space() && Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Returns `true` if the buffer after the current one is empty and writable.
+    ///
+    /// If not, a frame that does not fit in the rest of the current buffer is dropped.
+    pub(super) fn has_space(&self) -> bool {
+        critical_section::with(|_| {
+            let next_idx = (self.current_idx.load(Ordering::Relaxed) + 1) % N;
+            // SAFETY: We are in a critical section, so no defmt write is in progress, and
+            // the buffer is only read.
+            let next = unsafe { &*self.buffers[next_idx].get() };
+            next.writable() && next.cursor == 0
+        })
+    }
+
+    /// Returns `true` if no buffer needs to be flushed and the active buffer is empty.
+    fn is_drained(&self) -> bool {
+        critical_section::with(|_| {
+            let current_idx = self.current_idx.load(Ordering::Relaxed);
+            // SAFETY: We are in a critical section, so no defmt write is in progress, and
+            // the buffer is only read.
+            let current = unsafe { &*self.buffers[current_idx].get() };
+            current.cursor == 0 && !self.needs_flush()
+        })
+    }
+
+    /// Returns the sequence number for the next buffer sent, and advances it.
+    ///
+    /// This is only called while sending buffers, which is never done concurrently,
+    /// so a plain load and store is enough.
+    #[cfg(feature = "sequence-numbers")]
+    pub(super) fn next_sequence(&self) -> u32 {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), Ordering::Relaxed);
+        sequence
+    }
+
+    /// Returns `true` if any buffer, or the boot buffer, needs to be flushed to USB.
+    pub(super) fn needs_flush(&self) -> bool {
+        self.get_flushing().is_some() || crate::boot::pending().is_some()
+    }
+
     /// Get a buffer that needs to be flushed to USB.
     ///
-    /// Should _both_ buffers need flushing, it will flush the one at index 0 first.
+    /// Should several buffers need flushing, it returns the one marked as flushing first,
+    /// so that frames are sent in the order they were logged. `swap` only ever marks the
+    /// current buffer and moves on to the next index, so the buffers were marked in ring
+    /// order: the current buffer (which is flushing only if every buffer is) was marked
+    /// least recently, then the one after it, and so on. The first flushing buffer from
+    /// the current index is therefore the oldest.
     ///
     /// This is a purely a convenience for use in `flush`.
-    fn get_flushing(&self) -> Option<(usize, &LogBuffer)> {
-        for (idx, cell) in self.buffers.iter().enumerate() {
-            // SAFETY: swap, used in the defmt critical section, only ever marks a buffer as
-            // flushing (*never* as active), so if a buffer is marked as flushing it will not
-            // change until the caller of this function requests it to be reset.
-            let buf = unsafe { &*cell.get() };
-            if buf.is_flushing() {
-                return Some((idx, buf));
-            }
-        }
-        None
+    fn get_flushing(&self) -> Option<(usize, &LogBuffer<SIZE>)> {
+        let current_idx = self.current_idx.load(Ordering::Relaxed);
+        (0..N)
+            .map(|offset| (current_idx + offset) % N)
+            .find_map(|idx| {
+                // SAFETY: swap, used in the defmt critical section, only ever marks a buffer as
+                // flushing (*never* as active), so if a buffer is marked as flushing it will not
+                // change until the caller of this function requests it to be reset.
+                let buf = unsafe { &*self.buffers[idx].get() };
+                buf.is_flushing().then_some((idx, buf))
+            })
     }
 
     /// Return a buffer to service after it has been flushed.
@@ -167,22 +1143,714 @@ impl Controller {
         });
     }
 
-    pub(crate) async fn flush<F, E>(&self, mut flusher: F) -> Result<(), E>
+    /// Pass the contents of the buffers that need flushing to `flusher`.
+    ///
+    /// The first buffer marked as flushing is passed along with the buffers following it
+    /// that are also flushing, in the order they were filled, so that `flusher` can
+    /// send them as a single stream. Empty entries of the array are unused.
+    ///
+    /// Returns `true` if any bytes were passed to `flusher`, and `false` if there was
+    /// nothing to flush.
+    pub(crate) async fn flush<F, E>(&self, mut flusher: F) -> Result<bool, E>
     where
-        F: AsyncFnMut(&[u8]) -> Result<(), E>,
+        F: AsyncFnMut(&[&[u8]; N]) -> Result<(), E>,
     {
-        if let Some((buf_idx, buffer)) = self.get_flushing() {
-            // Only provide the used portion of the buffer.
-            let bytes = &buffer.data[..buffer.cursor];
-            let res = flusher(bytes).await;
-            // Always reset the buffer: this is the desired action in case of success,
-            // and unavoidable in case of error, because we cannot know how much of
-            // the buffer was sent.
-            self.reset_buffer(buf_idx);
-            // Propagate any error to the caller.
-            res?;
-        }
-        // Nothing to flush, or flush completed without issue.
-        Ok(())
+        let Some(first_idx) = self.start_send() else {
+            // Nothing to flush.
+            return Ok(false);
+        };
+
+        // SAFETY: The buffers were marked as being sent above, and are released below.
+        let contents = unsafe { self.sending_contents(first_idx) };
+        let res = flusher(&contents).await;
+        let len: usize = contents.iter().map(|bytes| bytes.len()).sum();
+        self.finish_send(len, res.is_ok());
+        // Propagate any error to the caller.
+        res?;
+        Ok(len > 0)
+    }
+
+    /// Mark the consecutive flushing buffers as being sent, so that they are not reset by
+    /// `disable` or `write` while being sent.
+    ///
+    /// This is done in a critical section so that they cannot be reset in between.
+    /// Returns the index of the first buffer to send, or `None` if there is nothing to
+    /// send. The buffers must be released with `finish_send` once sent.
+    pub(crate) fn start_send(&self) -> Option<usize> {
+        critical_section::with(|_| {
+            // Frames logged during boot were logged before any in the buffers.
+            if crate::boot::pending().is_some() {
+                self.sending.store(SENDING_BOOT, Ordering::Relaxed);
+                return Some(0);
+            }
+            let (first_idx, _) = self.get_flushing()?;
+            let mut sending = 0;
+            for offset in 0..N {
+                let idx = (first_idx + offset) % N;
+                // SAFETY: As in `get_flushing`, a buffer marked as flushing will not change
+                // until it is reset by `finish_send`.
+                let buffer = unsafe { &*self.buffers[idx].get() };
+                if !buffer.is_flushing() {
+                    break;
+                }
+                sending |= 1 << idx;
+            }
+            self.sending.store(sending, Ordering::Relaxed);
+            Some(first_idx)
+        })
+    }
+
+    /// Returns the used portion of the buffers being sent, in the order they were
+    /// filled, starting from `first_idx`. Empty entries of the array are unused.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called between `start_send`, which returned `first_idx`, and
+    /// `finish_send`, and the returned slices must not be used after `finish_send`.
+    pub(crate) unsafe fn sending_contents(&self, first_idx: usize) -> [&[u8]; N] {
+        let sending = self.sending.load(Ordering::Relaxed);
+        let mut contents: [&[u8]; N] = [&[]; N];
+        if sending & SENDING_BOOT != 0 {
+            contents[0] = crate::boot::pending().unwrap_or(&[]);
+            return contents;
+        }
+        for (offset, slot) in contents.iter_mut().enumerate() {
+            let idx = (first_idx + offset) % N;
+            if sending & (1 << idx) == 0 {
+                break;
+            }
+            // SAFETY: A buffer being sent is not changed until `finish_send` resets it.
+            let buffer = unsafe { &*self.buffers[idx].get() };
+            *slot = &buffer.data[..buffer.cursor];
+        }
+        contents
+    }
+
+    /// Release the buffers marked as being sent by `start_send`, after `len` bytes were
+    /// sent or sending them failed.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn finish_send(&self, len: usize, ok: bool) {
+        #[cfg(feature = "metrics")]
+        crate::stats::COUNTERS.record_flush(len, ok);
+        if !ok {
+            // Buffers are only ever sent by one task or interrupt at a time, so a plain
+            // load and store is enough.
+            let errors = self.flush_errors.load(Ordering::Relaxed);
+            self.flush_errors
+                .store(errors.wrapping_add(1), Ordering::Relaxed);
+        }
+        // Always reset the buffers: this is the desired action in case of success,
+        // and unavoidable in case of error, because we cannot know how much of
+        // the buffers was sent.
+        let sending = self.sending.load(Ordering::Relaxed);
+        for idx in (0..N).filter(|idx| sending & (1 << idx) != 0) {
+            self.reset_buffer(idx);
+        }
+        if sending & SENDING_BOOT != 0 {
+            crate::boot::finish();
+        }
+        self.sending.store(0, Ordering::Relaxed);
+        if self.is_drained() {
+            DRAINED.signal(());
+        }
+    }
+}
+
+// Channel tags add bytes to the frames, which the tests do not expect.
+#[cfg(all(test, not(any(feature = "channels", feature = "disabled"))))]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+    use crate::testing::host;
+
+    /// Logs a frame made of `parts`, as the defmt logger does.
+    fn log<const N: usize, const SIZE: usize>(controller: &Controller<N, SIZE>, parts: &[&[u8]]) {
+        critical_section::with(|_| {
+            // SAFETY: We are in a critical section, and the frame is started and ended.
+            unsafe {
+                controller.start_frame();
+                for part in parts {
+                    controller.write(part);
+                }
+                controller.end_frame();
+            }
+        });
+    }
+
+    /// Returns a frame of `len` bytes, all `byte`.
+    fn frame(byte: u8, len: usize) -> Vec<u8> {
+        vec![byte; len]
+    }
+
+    /// Flushes once, returning the contents passed to the flusher, one entry per buffer,
+    /// or `None` if there was nothing to flush.
+    fn flush<const N: usize, const SIZE: usize>(
+        controller: &Controller<N, SIZE>,
+    ) -> Option<Vec<Vec<u8>>> {
+        let mut sent = None;
+        let flushed = embassy_futures::block_on(controller.flush(async |contents: &[&[u8]; N]| {
+            sent = Some(
+                contents
+                    .iter()
+                    .filter(|bytes| !bytes.is_empty())
+                    .map(|bytes| bytes.to_vec())
+                    .collect(),
+            );
+            Ok::<(), ()>(())
+        }));
+        assert_eq!(flushed, Ok(sent.is_some()));
+        sent
+    }
+
+    /// Flushes until nothing is left, returning all the bytes sent.
+    fn drain<const N: usize, const SIZE: usize>(controller: &Controller<N, SIZE>) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let Some(contents) = flush(controller) {
+            bytes.extend(contents.concat());
+        }
+        bytes
+    }
+
+    /// Checks the controller's invariants, in addition to the checks of the tests.
+    fn check<const N: usize, const SIZE: usize>(controller: &Controller<N, SIZE>) {
+        // SAFETY: We are in a critical section.
+        critical_section::with(|_| unsafe { controller.debug_check() });
+    }
+
+    fn dropped<const N: usize, const SIZE: usize>(
+        controller: &Controller<N, SIZE>,
+    ) -> (usize, usize) {
+        (
+            controller.dropped_frames.load(Ordering::Relaxed),
+            controller.dropped_bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn frames_stay_in_the_active_buffer_until_it_fills() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 10)]);
+        log(&controller, &[&frame(2, 20)]);
+        check(&controller);
+        assert!(!controller.needs_flush());
+        assert_eq!(flush(&controller), None);
+        assert_eq!(controller.usage(), (30, 128));
+    }
+
+    #[test]
+    fn flush_current_sends_a_partial_buffer() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 10)]);
+        assert!(controller.flush_current());
+        check(&controller);
+        assert!(controller.needs_flush());
+        assert_eq!(flush(&controller), Some(vec![frame(1, 10)]));
+        assert!(controller.is_drained());
+    }
+
+    #[test]
+    fn flush_current_ignores_an_empty_buffer() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        assert!(!controller.flush_current());
+        assert_eq!(controller.current_idx.load(Ordering::Relaxed), 0);
+        assert_eq!(flush(&controller), None);
+    }
+
+    #[test]
+    fn frame_that_does_not_fit_moves_to_the_next_buffer() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        log(&controller, &[&frame(2, 30)]);
+        check(&controller);
+        assert_eq!(controller.current_idx.load(Ordering::Relaxed), 1);
+        assert_eq!(flush(&controller), Some(vec![frame(1, 40)]));
+        assert!(controller.flush_current());
+        assert_eq!(flush(&controller), Some(vec![frame(2, 30)]));
+        assert_eq!(dropped(&controller), (0, 0));
+    }
+
+    #[test]
+    fn frame_written_in_parts_moves_whole() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        // The first part fits after the first frame, the second does not.
+        log(&controller, &[&frame(2, 20), &frame(3, 20)]);
+        check(&controller);
+        assert_eq!(flush(&controller), Some(vec![frame(1, 40)]));
+        assert!(controller.flush_current());
+        assert_eq!(
+            flush(&controller),
+            Some(vec![[frame(2, 20), frame(3, 20)].concat()])
+        );
+    }
+
+    #[test]
+    fn new_frame_is_dropped_when_every_buffer_is_waiting() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        log(&controller, &[&frame(2, 40)]);
+        log(&controller, &[&frame(3, 10), &frame(4, 30)]);
+        check(&controller);
+        // The second buffer was sent on to make room, and the third frame dropped whole.
+        assert_eq!(dropped(&controller), (1, 40));
+        assert_eq!(
+            drain(&controller),
+            [frame(1, 40), frame(2, 40)].concat(),
+            "buffered frames were changed by the dropped frame"
+        );
+    }
+
+    #[test]
+    fn oldest_buffer_is_discarded_with_drop_oldest() {
+        let _lock = host::lock();
+        let controller = Controller::<3, 32>::new();
+        controller.set_overflow_policy(OverflowPolicy::DropOldest);
+        for byte in 1..=4 {
+            log(&controller, &[&frame(byte, 20)]);
+        }
+        check(&controller);
+        // The first frame's buffer was discarded to make room for the fourth.
+        assert_eq!(dropped(&controller), (1, 20));
+        assert!(controller.flush_current());
+        assert_eq!(
+            drain(&controller),
+            [frame(2, 20), frame(3, 20), frame(4, 20)].concat()
+        );
+    }
+
+    #[test]
+    fn oversized_frame_keeps_the_oldest_buffer_with_drop_oldest() {
+        let _lock = host::lock();
+        let controller = Controller::<3, 32>::new();
+        controller.set_overflow_policy(OverflowPolicy::DropOldest);
+        for byte in 1..=3 {
+            log(&controller, &[&frame(byte, 20)]);
+        }
+        log(&controller, &[&frame(4, 40)]);
+        check(&controller);
+        // Only the frame that could never fit was dropped.
+        assert_eq!(dropped(&controller), (1, 40));
+        assert_eq!(
+            drain(&controller),
+            [frame(1, 20), frame(2, 20), frame(3, 20)].concat()
+        );
+    }
+
+    #[test]
+    fn consecutive_flushing_buffers_are_sent_together() {
+        let _lock = host::lock();
+        let controller = Controller::<3, 32>::new();
+        log(&controller, &[&frame(1, 20)]);
+        log(&controller, &[&frame(2, 20)]);
+        log(&controller, &[&frame(3, 20)]);
+        check(&controller);
+        assert_eq!(flush(&controller), Some(vec![frame(1, 20), frame(2, 20)]));
+        assert_eq!(flush(&controller), None);
+        assert!(controller.flush_current());
+        assert_eq!(flush(&controller), Some(vec![frame(3, 20)]));
+    }
+
+    #[test]
+    fn buffer_being_sent_is_left_alone() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        assert!(controller.flush_current());
+        let first_idx = controller.start_send().unwrap();
+        // Frames logged and buffers discarded while sending do not touch the buffer.
+        log(&controller, &[&frame(2, 40)]);
+        log(&controller, &[&frame(3, 40)]);
+        controller.clear();
+        check(&controller);
+        // SAFETY: The buffers were marked as being sent, and are released below.
+        let contents = unsafe { controller.sending_contents(first_idx) };
+        assert_eq!(contents[0], &frame(1, 40)[..]);
+        controller.finish_send(40, true);
+        check(&controller);
+        assert!(controller.is_drained());
+        assert_eq!(dropped(&controller), (1, 40));
+    }
+
+    #[test]
+    fn clear_discards_every_buffered_frame() {
+        let _lock = host::lock();
+        let controller = Controller::<3, 32>::new();
+        log(&controller, &[&frame(1, 20)]);
+        log(&controller, &[&frame(2, 20)]);
+        log(&controller, &[&frame(3, 10)]);
+        assert!(controller.needs_flush());
+        controller.clear();
+        check(&controller);
+        assert!(controller.is_drained());
+        assert_eq!(controller.usage(), (0, 96));
+        assert_eq!(flush(&controller), None);
+        // Discarded frames are not counted as dropped.
+        assert_eq!(dropped(&controller), (0, 0));
+    }
+
+    #[test]
+    fn frames_logged_after_clear_are_sent() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        controller.clear();
+        assert!(controller.is_enabled());
+        log(&controller, &[&frame(2, 40)]);
+        log(&controller, &[&frame(3, 40)]);
+        check(&controller);
+        assert!(controller.flush_current());
+        assert_eq!(drain(&controller), [frame(2, 40), frame(3, 40)].concat());
+    }
+
+    #[test]
+    fn disabled_controller_ignores_frames() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 10)]);
+        controller.disable();
+        log(&controller, &[&frame(2, 10)]);
+        assert!(!controller.flush_current());
+        assert_eq!(flush(&controller), None);
+        controller.enable();
+        log(&controller, &[&frame(3, 10)]);
+        check(&controller);
+        assert!(controller.flush_current());
+        assert_eq!(flush(&controller), Some(vec![frame(3, 10)]));
+    }
+
+    #[test]
+    fn failed_flush_is_counted_and_frees_the_buffers() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        assert!(controller.flush_current());
+        let res = embassy_futures::block_on(controller.flush(async |_: &[&[u8]; 2]| Err(())));
+        assert_eq!(res, Err(()));
+        check(&controller);
+        assert_eq!(controller.flush_errors.load(Ordering::Relaxed), 1);
+        assert!(controller.is_drained());
+        // Both buffers can be filled again.
+        log(&controller, &[&frame(2, 40)]);
+        log(&controller, &[&frame(3, 40)]);
+        assert_eq!(dropped(&controller), (0, 0));
+    }
+
+    #[test]
+    fn buffers_are_reused_in_order_around_the_ring() {
+        let _lock = host::lock();
+        let controller = Controller::<3, 32>::new();
+        let mut expected = Vec::new();
+        let mut sent = Vec::new();
+        for byte in 1..=10 {
+            log(&controller, &[&frame(byte, 20)]);
+            expected.extend(frame(byte, 20));
+            check(&controller);
+            if byte % 2 == 0 {
+                // Send every other frame, so that buffers are freed mid-ring.
+                sent.extend(flush(&controller).unwrap().concat());
+            }
+        }
+        assert!(controller.flush_current());
+        sent.extend(drain(&controller));
+        assert_eq!(sent, expected);
+        assert_eq!(dropped(&controller), (0, 0));
+    }
+
+    #[test]
+    fn oldest_buffer_is_sent_first_after_wrapping_around() {
+        let _lock = host::lock();
+        let controller = Controller::<3, 32>::new();
+        // Fill and send the first two buffers, so that the next ones wrap around.
+        log(&controller, &[&frame(1, 20)]);
+        log(&controller, &[&frame(2, 20)]);
+        assert_eq!(flush(&controller), Some(vec![frame(1, 20)]));
+        log(&controller, &[&frame(3, 20)]);
+        assert_eq!(flush(&controller), Some(vec![frame(2, 20)]));
+        log(&controller, &[&frame(4, 20)]);
+        log(&controller, &[&frame(5, 20)]);
+        check(&controller);
+
+        // Buffers 2 and 0 are waiting, with buffer 2 filled first, so it comes first
+        // even though its index is higher.
+        assert_eq!(controller.current_idx.load(Ordering::Relaxed), 1);
+        assert_eq!(controller.get_flushing().map(|(idx, _)| idx), Some(2));
+        assert_eq!(flush(&controller), Some(vec![frame(3, 20), frame(4, 20)]));
+        assert!(controller.flush_current());
+        assert_eq!(flush(&controller), Some(vec![frame(5, 20)]));
+        assert_eq!(dropped(&controller), (0, 0));
+    }
+
+    #[test]
+    fn aborted_frame_is_removed() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 10)]);
+        critical_section::with(|_| {
+            // SAFETY: We are in a critical section, and the frame is aborted instead of
+            // ended.
+            unsafe {
+                controller.start_frame();
+                controller.write(&frame(2, 10));
+                controller.abort_frame();
+            }
+        });
+        check(&controller);
+        assert!(controller.flush_current());
+        assert_eq!(flush(&controller), Some(vec![frame(1, 10)]));
+    }
+
+    #[test]
+    fn swap_does_nothing_while_disabled() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 10)]);
+        controller.enabled.store(false, Ordering::Relaxed);
+        // SAFETY: We are in a critical section.
+        assert!(!critical_section::with(|_| unsafe { controller.swap() }));
+        assert_eq!(controller.current_idx.load(Ordering::Relaxed), 0);
+        controller.enabled.store(true, Ordering::Relaxed);
+        check(&controller);
+    }
+
+    #[test]
+    fn frame_longer_than_a_buffer_is_dropped() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 10)]);
+        log(&controller, &[&frame(2, 30), &frame(3, 30), &frame(4, 30)]);
+        check(&controller);
+        assert_eq!(dropped(&controller), (1, 90));
+        // Frames after it are kept.
+        log(&controller, &[&frame(5, 10)]);
+        assert!(controller.flush_current());
+        assert_eq!(drain(&controller), [frame(1, 10), frame(5, 10)].concat());
+    }
+
+    #[test]
+    fn frame_longer_than_a_buffer_is_truncated() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_long_frame_policy(LongFramePolicy::Truncate);
+        let long = [frame(2, 30), frame(3, 30), frame(4, 30)];
+        log(&controller, &[&long[0], &long[1], &long[2]]);
+        check(&controller);
+        // The start of the frame is kept, up to the marker, and the rest counted as
+        // dropped bytes of a frame that was not dropped.
+        let limit = 64 - 1 - TRUNCATION_MARKER.len();
+        assert_eq!(dropped(&controller), (0, 90 - limit));
+        log(&controller, &[&frame(5, 10)]);
+        assert!(controller.flush_current());
+        let expected = [&long.concat()[..limit], &TRUNCATION_MARKER, &frame(5, 10)].concat();
+        assert_eq!(drain(&controller), expected);
+    }
+
+    #[test]
+    fn truncated_frame_moves_to_an_empty_buffer() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_long_frame_policy(LongFramePolicy::Truncate);
+        log(&controller, &[&frame(1, 40)]);
+        log(&controller, &[&frame(2, 20), &frame(3, 80)]);
+        check(&controller);
+        let limit = 64 - 1 - TRUNCATION_MARKER.len();
+        assert_eq!(dropped(&controller), (0, 100 - limit));
+        // The frame filled the buffer, which was marked as flushing.
+        assert!(!controller.flush_current());
+        let long = [frame(2, 20), frame(3, 80)].concat();
+        let expected = [&frame(1, 40), &long[..limit], &TRUNCATION_MARKER].concat();
+        assert_eq!(drain(&controller), expected);
+    }
+
+    #[test]
+    fn long_frame_is_dropped_when_no_buffer_has_room_to_truncate() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_long_frame_policy(LongFramePolicy::Truncate);
+        log(&controller, &[&frame(1, 40)]);
+        // The frame starts too close to the end of the buffer for the marker, and the
+        // other buffer is waiting to be sent.
+        log(&controller, &[&frame(2, 60)]);
+        log(&controller, &[&frame(3, 70)]);
+        check(&controller);
+        assert_eq!(dropped(&controller), (1, 70));
+        assert_eq!(drain(&controller), [frame(1, 40), frame(2, 60)].concat());
+    }
+
+    #[test]
+    fn dropped_counters_are_taken_once() {
+        let _lock = host::lock();
+        log(&CONTROLLER, &[&frame(1, BUFFERSIZE + 10)]);
+        log(&CONTROLLER, &[&frame(2, BUFFERSIZE)]);
+        assert_eq!(dropped_frames(), 2);
+        assert_eq!(dropped_bytes(), 2 * BUFFERSIZE + 10);
+        assert_eq!(take_dropped_frames(), 2);
+        assert_eq!(take_dropped_bytes(), 2 * BUFFERSIZE + 10);
+        assert_eq!((dropped_frames(), dropped_bytes()), (0, 0));
+    }
+
+    #[test]
+    fn full_buffers_drop_frames_without_waiting() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        // Nothing is ever sent, so a write path that waited for room would never return.
+        for byte in 0..=255 {
+            controller.wait_for_space();
+            log(&controller, &[&frame(byte, 20), &frame(byte, 20)]);
+        }
+        check(&controller);
+        assert!(!controller.has_space());
+        // The first frame of each buffer was kept.
+        assert_eq!(dropped(&controller), (254, 254 * 40));
+        assert_eq!(drain(&controller), [frame(0, 40), frame(1, 40)].concat());
+    }
+
+    #[test]
+    fn peak_buffer_usage_is_kept_until_reset() {
+        let _lock = host::lock();
+        log(&CONTROLLER, &[&frame(1, 10)]);
+        log(&CONTROLLER, &[&frame(2, 30)]);
+        assert_eq!(peak_buffer_usage(), 40);
+        // Sending the buffer does not lower the peak.
+        assert!(CONTROLLER.flush_current());
+        drain(&CONTROLLER);
+        log(&CONTROLLER, &[&frame(3, 5)]);
+        assert_eq!(peak_buffer_usage(), 40);
+        reset_peak_buffer_usage();
+        assert_eq!(peak_buffer_usage(), 0);
+        log(&CONTROLLER, &[&frame(4, 7)]);
+        assert_eq!(peak_buffer_usage(), 12);
+    }
+
+    #[test]
+    fn peak_buffer_usage_counts_a_single_buffer() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 40)]);
+        log(&controller, &[&frame(2, 30)]);
+        // The second frame moved to the other buffer.
+        assert_eq!(controller.peak_usage.load(Ordering::Relaxed), 40);
+        assert_eq!(controller.usage(), (70, 128));
+        // A dropped frame is not counted.
+        log(&controller, &[&frame(3, 60)]);
+        assert_eq!(controller.peak_usage.load(Ordering::Relaxed), 40);
+    }
+
+    /// Fills both buffers of `controller`, so that the next frame has no room.
+    fn fill(controller: &Controller<2, 64>) {
+        log(controller, &[&frame(1, 40)]);
+        log(controller, &[&frame(2, 40)]);
+        assert!(!controller.has_space());
+    }
+
+    #[test]
+    fn lossless_mode_does_not_wait_with_room() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_mode(Mode::Lossless);
+        log(&controller, &[&frame(1, 40)]);
+        let start = Instant::now();
+        controller.wait_for_space();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn lossless_mode_waits_for_a_buffer_to_be_sent() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_mode(Mode::Lossless);
+        fill(&controller);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                flush(&controller);
+            });
+            controller.wait_for_space();
+        });
+        assert!(start.elapsed() < LOSSLESS_TIMEOUT);
+        assert!(controller.has_space());
+        log(&controller, &[&frame(3, 40)]);
+        assert_eq!(dropped(&controller), (0, 0));
+    }
+
+    #[test]
+    fn lossless_mode_gives_up_after_the_timeout() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_mode(Mode::Lossless);
+        fill(&controller);
+        let start = Instant::now();
+        controller.wait_for_space();
+        assert!(start.elapsed() >= LOSSLESS_TIMEOUT);
+        // The frame is then dropped as in `Mode::Drop`.
+        log(&controller, &[&frame(3, 40)]);
+        assert_eq!(dropped(&controller), (1, 40));
+    }
+
+    #[test]
+    fn lossless_mode_stops_waiting_when_disabled() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_mode(Mode::Lossless);
+        fill(&controller);
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                std::thread::sleep(std::time::Duration::from_millis(10));
+                controller.disable();
+            });
+            controller.wait_for_space();
+        });
+        assert!(start.elapsed() < LOSSLESS_TIMEOUT);
+    }
+
+    #[test]
+    fn buffer_is_flushed_at_the_watermark() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_watermark(32);
+        log(&controller, &[&frame(1, 20)]);
+        log(&controller, &[&frame(2, 12)]);
+        assert!(controller.needs_flush());
+        assert_eq!(controller.current_idx.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            flush(&controller),
+            Some(vec![[frame(1, 20), frame(2, 12)].concat()])
+        );
+    }
+
+    #[test]
+    fn buffer_is_not_flushed_below_the_watermark() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        controller.set_watermark(32);
+        log(&controller, &[&frame(1, 20)]);
+        log(&controller, &[&frame(2, 11)]);
+        assert!(!controller.needs_flush());
+        assert_eq!(controller.current_idx.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn default_watermark_flushes_two_bytes_before_full() {
+        let _lock = host::lock();
+        let controller = Controller::<2, 64>::new();
+        log(&controller, &[&frame(1, 61)]);
+        assert!(!controller.needs_flush());
+        log(&controller, &[&frame(2, 1)]);
+        assert!(controller.needs_flush());
+        // Watermarks past the buffer size are clamped to it.
+        let controller = Controller::<2, 64>::new();
+        controller.set_watermark(1000);
+        log(&controller, &[&frame(1, 62)]);
+        assert!(controller.needs_flush());
     }
 }
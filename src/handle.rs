@@ -0,0 +1,114 @@
+//! Handle to control the logger at runtime.
+
+use embassy_time::Duration;
+
+#[cfg(not(feature = "disabled"))]
+use crate::controller::CONTROLLER;
+use crate::{
+    controller::{LongFramePolicy, Mode, OverflowPolicy, StartupPolicy},
+    level::Level,
+};
+
+/// Handle to enable and disable the logger at runtime.
+///
+/// The logger's state is global, so the handle carries no data and can be created
+/// wherever it is needed.
+///
+/// Note that the logger task also enables the logger when the USB device is connected,
+/// and disables it when the device is disconnected.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoggerHandle;
+
+impl LoggerHandle {
+    /// Enables the logger, so that defmt frames are buffered and sent.
+    pub fn enable(&self) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.enable();
+    }
+
+    /// Disables the logger, so that defmt frames are silently ignored.
+    ///
+    /// Any buffered frames that have not been sent are discarded.
+    pub fn disable(&self) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.disable();
+    }
+
+    /// Discards the buffered frames that have not been sent, leaving the logger enabled.
+    ///
+    /// Unlike [`disable`](Self::disable), frames logged afterwards are buffered and sent
+    /// as usual, so this starts afresh from this point, for instance at the start of a
+    /// new test phase. The buffers being sent at the time are still sent.
+    pub fn clear(&self) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.clear();
+    }
+
+    /// Sets what the logger does with frames when the buffers are full.
+    ///
+    /// The default is [`Mode::Drop`]. See [`Mode::Lossless`] for the trade-offs of
+    /// waiting for a free buffer instead.
+    pub fn set_mode(&self, mode: Mode) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.set_mode(mode);
+    }
+
+    /// Sets which frames are dropped when the buffers are full.
+    ///
+    /// The default is [`OverflowPolicy::DropNewest`].
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.set_overflow_policy(policy);
+    }
+
+    /// Sets what the logger does with frames too long to fit in a buffer.
+    ///
+    /// The default is [`LongFramePolicy::Drop`].
+    pub fn set_long_frame_policy(&self, policy: LongFramePolicy) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.set_long_frame_policy(policy);
+    }
+
+    /// Sets what the logger does with frames logged before the host first attaches.
+    ///
+    /// The default is [`StartupPolicy::Buffer`]. This should be called at the start of
+    /// the program, before logging, and has no effect once the host has attached.
+    pub fn set_startup_policy(&self, policy: StartupPolicy) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.set_startup_policy(policy);
+    }
+
+    /// Sets the time the logger task waits between flushes, overriding
+    /// [`FlushConfig::flush_interval`](crate::FlushConfig::flush_interval).
+    ///
+    /// The change takes effect on the next iteration of the task, which is woken
+    /// straight away, so the interval can be shortened while an interactive debug
+    /// session is open and relaxed afterwards. The
+    /// [`min_flush_interval`](crate::FlushConfig::min_flush_interval) and
+    /// [`max_flush_interval`](crate::FlushConfig::max_flush_interval) bounds still
+    /// apply, when set.
+    pub fn set_flush_interval(&self, interval: Duration) {
+        crate::task::set_flush_interval(interval);
+    }
+
+    /// Sets the level at or above which frames logged with this crate's logging macros,
+    /// such as [`info!`](crate::info), are sent straight away, as with
+    /// [`request_flush`](crate::request_flush).
+    ///
+    /// `None`, the default, never flushes because of the level. Frames logged with the
+    /// defmt macros directly are not affected, as the logger cannot see their level.
+    pub fn set_flush_level(&self, level: Option<Level>) {
+        crate::level::set_flush_level(level);
+    }
+
+    /// Sets the number of buffered bytes at which a buffer is sent, without waiting
+    /// for it to fill.
+    ///
+    /// A lower watermark reduces the latency of moderate logging, at the cost of
+    /// sending smaller transfers. The default is the buffer size, so buffers are only
+    /// sent once full; larger values are clamped to it.
+    pub fn set_flush_watermark(&self, bytes: usize) {
+        #[cfg(not(feature = "disabled"))]
+        CONTROLLER.set_watermark(bytes);
+    }
+}
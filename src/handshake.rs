@@ -0,0 +1,41 @@
+//! Handshake record sent when the logger connects, with
+//! [`FlushConfig::send_handshake`](crate::FlushConfig::send_handshake).
+//!
+//! The handshake is sent by the logger task each time the host attaches, before any
+//! buffered frame, so that host tools can configure themselves, for instance to size
+//! their reads to the packets the device sends. It is [`HANDSHAKE_LEN`] bytes long:
+//!
+//! - `0x00`, ending any partial frame before it.
+//! - The two [`MARKER`] bytes, `HS`.
+//! - The maximum packet size of the logger's endpoint, in three bytes of seven bits
+//!   each, least significant first, with the high bit of each byte set.
+//! - `0x00`, so that the handshake is delimited like a defmt frame.
+//!
+//! Like the headers of the `sequence-numbers` feature, the handshake never contains a
+//! `0x00` byte other than its delimiters, but a decoder that is not aware of it sees a
+//! malformed frame, so it is off by default. In Python:
+//!
+//! ```python
+//! HANDSHAKE = re.compile(rb"\x00HS([\x80-\xff]{3})\x00")
+//!
+//! def packet_size(match):
+//!     return sum((b & 0x7F) << (7 * i) for i, b in enumerate(match.group(1)))
+//! ```
+//!
+//! With the `compression` feature, the handshake is compressed like the frames.
+
+/// Marker bytes identifying the handshake.
+pub const MARKER: [u8; 2] = *b"HS";
+
+/// Length of the handshake in bytes.
+pub const HANDSHAKE_LEN: usize = 7;
+
+/// Returns the handshake for the given maximum packet size.
+pub(crate) fn handshake(packet_size: usize) -> [u8; HANDSHAKE_LEN] {
+    let mut handshake = [0; HANDSHAKE_LEN];
+    handshake[1..3].copy_from_slice(&MARKER);
+    for (i, byte) in handshake[3..6].iter_mut().enumerate() {
+        *byte = 0x80 | ((packet_size >> (7 * i)) & 0x7F) as u8;
+    }
+    handshake
+}
@@ -0,0 +1,182 @@
+//! History of the most recent log frames, readable over the control endpoint, enabled
+//! with the `history` feature.
+//!
+//! Every byte written to the buffers is also written to a ring of [`HISTORY_SIZE`]
+//! bytes, including frames that are then dropped because the buffers are full, so that
+//! the host can read back what was logged before it connected. The history is read
+//! with vendor control requests to the device (`bmRequestType` `0xC0`):
+//!
+//! - [`REQUEST_INFO`] returns eight bytes: the position of the oldest byte held and the
+//!   position after the newest byte, as little-endian `u32`s. Positions count every
+//!   byte ever written to the history, wrapping at `2^32`.
+//! - [`REQUEST_READ`] returns up to `wLength` bytes starting at the position given by
+//!   `wValue` (low half) and `wIndex` (high half). Bytes that are no longer held, or
+//!   not yet written, are not returned, so a short or empty response means the end of
+//!   the history was reached or the position was overwritten in the meantime.
+//!
+//! The oldest frame held is usually partial, as it was overwritten from its start: the
+//! host should discard the bytes up to the first `0x00` before decoding. The bytes are
+//! those written to the buffers, so they carry channel tags but not sequence number
+//! headers, and are not compressed. In Python, with pyusb:
+//!
+//! ```python
+//! start, end = struct.unpack("<II", dev.ctrl_transfer(0xC0, 0xD0, 0, 0, 8))
+//! history = b""
+//! while start != end:
+//!     chunk = dev.ctrl_transfer(0xC0, 0xD1, start & 0xFFFF, start >> 16, 256)
+//!     if not chunk:
+//!         break
+//!     history += bytes(chunk)
+//!     start = (start + len(chunk)) % 2**32
+//! history = history[history.index(0) + 1:]
+//! ```
+
+use core::{cell::UnsafeCell, sync::atomic::Ordering};
+
+use embassy_usb::{
+    control::{InResponse, Recipient, Request, RequestType},
+    driver::Driver,
+    Builder, Handler,
+};
+use portable_atomic::{AtomicU32, AtomicUsize};
+use static_cell::ConstStaticCell;
+
+/// Number of bytes held in the history.
+pub const HISTORY_SIZE: usize = 1024;
+
+/// Vendor request returning the positions of the oldest and newest bytes held.
+pub const REQUEST_INFO: u8 = 0xD0;
+
+/// Vendor request returning the bytes held from a position.
+pub const REQUEST_READ: u8 = 0xD1;
+
+// Positions wrap at 2^32, so they only map consistently onto the ring if its size
+// divides 2^32.
+const _: () = assert!(HISTORY_SIZE.is_power_of_two());
+
+/// The history of the logger.
+static HISTORY: History = History::new();
+
+/// Handler registered on the USB device to answer history requests.
+static HANDLER: ConstStaticCell<HistoryHandler> = ConstStaticCell::new(HistoryHandler);
+
+/// Ring of the most recent bytes written to the buffers.
+struct History {
+    /// Position after the newest byte.
+    end: AtomicU32,
+    /// Number of bytes held, at most `HISTORY_SIZE`.
+    len: AtomicUsize,
+    /// Bytes held, each at its position modulo `HISTORY_SIZE`.
+    data: UnsafeCell<[u8; HISTORY_SIZE]>,
+}
+
+// Sync is required for types in static variables.
+//
+// SAFETY: The data is only accessed within a critical section.
+unsafe impl Sync for History {}
+
+impl History {
+    /// Static initializer.
+    const fn new() -> Self {
+        Self {
+            end: AtomicU32::new(0),
+            len: AtomicUsize::new(0),
+            data: UnsafeCell::new([0; HISTORY_SIZE]),
+        }
+    }
+
+    /// Returns the positions of the oldest byte held and after the newest byte.
+    fn bounds(&self) -> (u32, u32) {
+        critical_section::with(|_| {
+            let end = self.end.load(Ordering::Relaxed);
+            let len = self.len.load(Ordering::Relaxed);
+            (end.wrapping_sub(len as u32), end)
+        })
+    }
+
+    /// Copies the bytes held from `position` into `out`, returning the number copied.
+    fn read(&self, position: u32, out: &mut [u8]) -> usize {
+        critical_section::with(|_| {
+            let end = self.end.load(Ordering::Relaxed);
+            let len = self.len.load(Ordering::Relaxed);
+            // Number of bytes held from `position`, which wraps past `len` if the
+            // position is no longer, or not yet, held.
+            let available = end.wrapping_sub(position) as usize;
+            if available > len {
+                return 0;
+            }
+            // SAFETY: We are in a critical section, and the data is only read.
+            let data = unsafe { &*self.data.get() };
+            let count = available.min(out.len());
+            for (offset, byte) in out[..count].iter_mut().enumerate() {
+                *byte = data[position.wrapping_add(offset as u32) as usize % HISTORY_SIZE];
+            }
+            count
+        })
+    }
+}
+
+/// Write bytes to the history, overwriting the oldest.
+///
+/// # Safety
+///
+/// Must be called from within a critical section.
+#[inline]
+pub(crate) unsafe fn record(bytes: &[u8]) {
+    // Only the last `HISTORY_SIZE` bytes would be kept.
+    let skipped = bytes.len().saturating_sub(HISTORY_SIZE);
+    let mut end = HISTORY
+        .end
+        .load(Ordering::Relaxed)
+        .wrapping_add(skipped as u32);
+    // SAFETY: We are in a critical section, guaranteed by the caller.
+    let data = unsafe { &mut *HISTORY.data.get() };
+    for &byte in &bytes[skipped..] {
+        data[end as usize % HISTORY_SIZE] = byte;
+        end = end.wrapping_add(1);
+    }
+    HISTORY.end.store(end, Ordering::Relaxed);
+    let len = HISTORY.len.load(Ordering::Relaxed);
+    HISTORY
+        .len
+        .store((len + bytes.len()).min(HISTORY_SIZE), Ordering::Relaxed);
+}
+
+/// USB device handler answering history requests.
+struct HistoryHandler;
+
+impl Handler for HistoryHandler {
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Vendor || req.recipient != Recipient::Device {
+            return None;
+        }
+        let requested = usize::from(req.length).min(buf.len());
+        match req.request {
+            REQUEST_INFO if requested >= 8 => {
+                let (start, end) = HISTORY.bounds();
+                buf[..4].copy_from_slice(&start.to_le_bytes());
+                buf[4..8].copy_from_slice(&end.to_le_bytes());
+                Some(InResponse::Accepted(&buf[..8]))
+            }
+            REQUEST_INFO => Some(InResponse::Rejected),
+            REQUEST_READ => {
+                let position = u32::from(req.value) | (u32::from(req.index) << 16);
+                let count = HISTORY.read(position, &mut buf[..requested]);
+                Some(InResponse::Accepted(&buf[..count]))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Registers a handler on the USB builder so that the host can read the history.
+///
+/// This is done by the `run` functions. It is only needed when building the USB device
+/// yourself, and may only be called once.
+///
+/// # Panics
+///
+/// Panics if called more than once.
+pub fn add_history_handler<'d, D: Driver<'d>>(builder: &mut Builder<'d, D>) {
+    builder.handler(HANDLER.take());
+}
@@ -0,0 +1,222 @@
+//! Flushing driven from a USB interrupt handler, without an async executor.
+//!
+//! The [`logger`](crate::logger) task needs an async executor and embassy-usb. With
+//! another USB stack, for instance in an RTIC or bare interrupt setup, the buffers can
+//! instead be sent by calling [`on_usb_interrupt`] from the USB interrupt handler, with
+//! a [`PollTransport`] wrapping the stack's serial or bulk endpoint:
+//!
+//! ```ignore
+//! #[interrupt]
+//! fn USBCTRL_IRQ() {
+//!     let serial = /* the application's serial port */;
+//!     defmtusb::on_usb_interrupt(&mut SerialTransport(serial));
+//! }
+//! ```
+//!
+//! Each call sends as many packets as the endpoint accepts without blocking, and picks
+//! up where it left off on the next call, once the endpoint is ready again. Only one
+//! of [`on_usb_interrupt`] and the logger task may be used in a program.
+
+#[cfg(not(feature = "disabled"))]
+use core::cell::Cell;
+
+#[cfg(not(feature = "disabled"))]
+use critical_section::Mutex;
+
+use crate::transport::TransportError;
+#[cfg(not(feature = "disabled"))]
+use crate::{controller::CONTROLLER, transfer::Transfer};
+
+/// Progress of sending the buffers between calls to [`on_usb_interrupt`].
+#[cfg(not(feature = "disabled"))]
+static STATE: Mutex<Cell<State>> = Mutex::new(Cell::new(State::new()));
+
+/// Error returned by a [`PollTransport`] when trying to send a packet.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PollError {
+    /// The endpoint cannot accept a packet yet. The packet is sent again on the next
+    /// call to [`on_usb_interrupt`].
+    WouldBlock,
+    /// Sending the packet failed. The rest of the buffers being sent is discarded, and
+    /// with [`TransportError::Disabled`] the logger is disabled.
+    Transport(TransportError),
+}
+
+impl From<TransportError> for PollError {
+    fn from(e: TransportError) -> Self {
+        Self::Transport(e)
+    }
+}
+
+/// A transport that sends buffered defmt frames to the host without blocking, one
+/// packet at a time.
+pub trait PollTransport {
+    /// Try to send a single packet of at most
+    /// [`max_packet_size`](Self::max_packet_size) bytes, without blocking.
+    ///
+    /// An empty packet may be sent to terminate a transfer.
+    fn try_send(&mut self, packet: &[u8]) -> Result<(), PollError>;
+
+    /// The maximum number of bytes that can be sent in a single packet.
+    fn max_packet_size(&self) -> usize;
+
+    /// Returns `true` if the device is configured by the host, so that packets can be
+    /// sent.
+    fn is_connected(&self) -> bool;
+}
+
+/// Sends buffered frames over the transport, without blocking.
+///
+/// This is meant to be called from the USB interrupt handler, so that packets are sent
+/// whenever the endpoint becomes ready. It enables the logger when the transport is
+/// connected and disables it when disconnected, as the logger task does. When nothing
+/// is waiting to be sent, the active buffer is sent if it holds any frames, so frames
+/// are sent as soon as the endpoint is free rather than after a flush interval.
+///
+/// Must always be called from the same interrupt priority, so that calls never
+/// preempt each other, and not together with the logger task.
+pub fn on_usb_interrupt<T: PollTransport>(transport: &mut T) {
+    // Nothing is ever buffered when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        let mut state = critical_section::with(|cs| STATE.borrow(cs).get());
+
+        let connected = transport.is_connected();
+        if connected != state.connected {
+            state.connected = connected;
+            if connected {
+                CONTROLLER.attach();
+            } else {
+                state.abandon();
+                CONTROLLER.disable();
+            }
+        }
+
+        if connected && CONTROLLER.is_enabled() {
+            state.poll(transport);
+        }
+
+        critical_section::with(|cs| STATE.borrow(cs).set(state));
+    }
+}
+
+/// Progress of sending the buffers.
+#[cfg(not(feature = "disabled"))]
+#[derive(Clone, Copy)]
+struct State {
+    /// The transport was connected on the last call.
+    connected: bool,
+    /// The buffers being sent, if any.
+    sending: Option<Sending>,
+}
+
+/// Buffers being sent, which are kept marked as sending between calls.
+#[cfg(not(feature = "disabled"))]
+#[derive(Clone, Copy)]
+struct Sending {
+    /// Index of the first buffer being sent.
+    first_idx: usize,
+    /// Number of packets already sent, including a zero-length packet.
+    packets_sent: usize,
+    /// Headers of the buffers being sent, numbered when sending starts.
+    #[cfg(feature = "sequence-numbers")]
+    headers: [[u8; crate::sequence::HEADER_LEN]; crate::controller::BUFFERCOUNT],
+}
+
+#[cfg(not(feature = "disabled"))]
+impl State {
+    /// Static initializer.
+    const fn new() -> Self {
+        Self {
+            connected: false,
+            sending: None,
+        }
+    }
+
+    /// Release the buffers being sent, if any, as failed.
+    fn abandon(&mut self) {
+        if self.sending.take().is_some() {
+            CONTROLLER.finish_send(0, false);
+        }
+    }
+
+    /// Send packets of the buffers waiting to be sent until the transport would block or
+    /// they have all been sent.
+    fn poll<T: PollTransport>(&mut self, transport: &mut T) {
+        let sending = match &mut self.sending {
+            Some(sending) => sending,
+            None => {
+                // Send the active buffer straight away if nothing else is waiting.
+                if !CONTROLLER.needs_flush() {
+                    CONTROLLER.flush_current();
+                }
+                let Some(first_idx) = CONTROLLER.start_send() else {
+                    return;
+                };
+                self.sending.insert(Sending::new(first_idx))
+            }
+        };
+
+        match sending.send(transport) {
+            Err(PollError::WouldBlock) => (),
+            Ok(len) => {
+                self.sending = None;
+                CONTROLLER.finish_send(len, true);
+            }
+            Err(PollError::Transport(e)) => {
+                self.abandon();
+                if e == TransportError::Disabled {
+                    CONTROLLER.disable();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "disabled"))]
+impl Sending {
+    /// Start sending the buffers from `first_idx`, which were marked as being sent.
+    fn new(first_idx: usize) -> Self {
+        #[cfg_attr(not(feature = "sequence-numbers"), allow(unused_mut))]
+        let mut sending = Self {
+            first_idx,
+            packets_sent: 0,
+            #[cfg(feature = "sequence-numbers")]
+            headers: [[0; crate::sequence::HEADER_LEN]; crate::controller::BUFFERCOUNT],
+        };
+        #[cfg(feature = "sequence-numbers")]
+        {
+            // SAFETY: The buffers were marked as being sent by the caller.
+            let buffers = unsafe { CONTROLLER.sending_contents(first_idx) };
+            crate::sequence::number(&buffers, &mut sending.headers);
+        }
+        sending
+    }
+
+    /// Send the packets not yet sent, returning the number of bytes in the buffers once
+    /// all have been sent.
+    fn send<T: PollTransport>(&mut self, transport: &mut T) -> Result<usize, PollError> {
+        let packet_size = transport.max_packet_size();
+
+        // SAFETY: The buffers stay marked as being sent until `finish_send` is called
+        // once this returns `Ok` or a transport error.
+        let buffers = unsafe { CONTROLLER.sending_contents(self.first_idx) };
+        let len = buffers.iter().map(|bytes| bytes.len()).sum();
+        #[cfg(feature = "sequence-numbers")]
+        let buffers = &crate::sequence::interleave(&buffers, &self.headers);
+
+        // The packets are produced the same way on every call, so skip those already
+        // sent. A transfer ending in a full-size packet is terminated, as the logger task
+        // does.
+        let mut transfer = Transfer::new(buffers.as_slice(), packet_size, true);
+        let mut idx = 0;
+        while let Some(chunk) = transfer.next_packet() {
+            if idx >= self.packets_sent {
+                transport.try_send(chunk)?;
+                self.packets_sent += 1;
+            }
+            idx += 1;
+        }
+        Ok(len)
+    }
+}
@@ -0,0 +1,165 @@
+//! Flushing of frames at or above a log level.
+//!
+//! defmt does not tell the logger the level of the frame being logged, so the level is
+//! only known where the frame is logged. The [`error!`](crate::error),
+//! [`warn!`](crate::warn), [`info!`](crate::info), [`debug!`](crate::debug) and
+//! [`trace!`](crate::trace) macros log with the defmt macro of the same name, then
+//! flush the buffers if a frame was logged and its level is at or above the one set
+//! with [`LoggerHandle::set_flush_level`](crate::LoggerHandle::set_flush_level):
+//!
+//! ```ignore
+//! defmtusb::LoggerHandle.set_flush_level(Some(defmtusb::Level::Info));
+//!
+//! defmtusb::info!("connected to {}", address); // Sent straight away.
+//! defmtusb::debug!("rssi: {}", rssi); // Batched with later frames.
+//! ```
+
+use core::sync::atomic::Ordering;
+
+use portable_atomic::AtomicU8;
+
+/// Level at or above which frames are flushed, or `NO_LEVEL`.
+static FLUSH_LEVEL: AtomicU8 = AtomicU8::new(NO_LEVEL);
+
+/// Value of `FLUSH_LEVEL` when frames are never flushed because of their level.
+const NO_LEVEL: u8 = u8::MAX;
+
+/// Level of a log frame, from least to most severe.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum Level {
+    /// Logged with `trace!`.
+    Trace,
+    /// Logged with `debug!`.
+    Debug,
+    /// Logged with `info!`.
+    Info,
+    /// Logged with `warn!`.
+    Warn,
+    /// Logged with `error!`.
+    Error,
+}
+
+/// Sets the level at or above which frames logged with this crate's macros are
+/// flushed, or `None` to never flush because of the level.
+pub(crate) fn set_flush_level(level: Option<Level>) {
+    FLUSH_LEVEL.store(
+        level.map_or(NO_LEVEL, |level| level as u8),
+        Ordering::Relaxed,
+    );
+}
+
+/// Flushes the buffers if a frame was logged since `started` was read from
+/// [`frames_started`](crate::frames_started), and `level` is at or above the flush
+/// level.
+///
+/// Called by the logging macros after each frame. A level filtered out with `DEFMT_LOG`
+/// logs nothing, so nothing is flushed for it.
+#[doc(hidden)]
+#[inline]
+pub fn flush_logged(level: Level, started: u32) {
+    let flush_level = FLUSH_LEVEL.load(Ordering::Relaxed);
+    if flush_level != NO_LEVEL && level as u8 >= flush_level && crate::frames_started() != started {
+        crate::request_flush();
+    }
+}
+
+/// Logs at the error level with [`defmt::error!`], flushing if the level is at or above
+/// the flush level.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        let started = $crate::frames_started();
+        ::defmt::error!($($arg)*);
+        $crate::flush_logged($crate::Level::Error, started);
+    }};
+}
+
+/// Logs at the warn level with [`defmt::warn!`], flushing if the level is at or above
+/// the flush level.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        let started = $crate::frames_started();
+        ::defmt::warn!($($arg)*);
+        $crate::flush_logged($crate::Level::Warn, started);
+    }};
+}
+
+/// Logs at the info level with [`defmt::info!`], flushing if the level is at or above
+/// the flush level.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        let started = $crate::frames_started();
+        ::defmt::info!($($arg)*);
+        $crate::flush_logged($crate::Level::Info, started);
+    }};
+}
+
+/// Logs at the debug level with [`defmt::debug!`], flushing if the level is at or above
+/// the flush level.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        let started = $crate::frames_started();
+        ::defmt::debug!($($arg)*);
+        $crate::flush_logged($crate::Level::Debug, started);
+    }};
+}
+
+/// Logs at the trace level with [`defmt::trace!`], flushing if the level is at or above
+/// the flush level.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        let started = $crate::frames_started();
+        ::defmt::trace!($($arg)*);
+        $crate::flush_logged($crate::Level::Trace, started);
+    }};
+}
+
+// Channel tags add bytes to the frames, which the tests do not expect.
+#[cfg(all(test, not(any(feature = "channels", feature = "disabled"))))]
+mod tests {
+    use super::*;
+    use crate::{controller::CONTROLLER, testing::host};
+
+    #[test]
+    fn frame_at_the_flush_level_is_flushed() {
+        let _lock = host::lock();
+        set_flush_level(Some(Level::Info));
+        let started = crate::frames_started();
+        crate::write_raw_frame(&[1; 10]);
+        flush_logged(Level::Info, started);
+        assert!(CONTROLLER.needs_flush());
+    }
+
+    #[test]
+    fn frame_below_the_flush_level_is_not_flushed() {
+        let _lock = host::lock();
+        set_flush_level(Some(Level::Info));
+        let started = crate::frames_started();
+        crate::write_raw_frame(&[1; 10]);
+        flush_logged(Level::Debug, started);
+        assert!(!CONTROLLER.needs_flush());
+    }
+
+    #[test]
+    fn level_that_logged_nothing_does_not_flush() {
+        let _lock = host::lock();
+        set_flush_level(Some(Level::Trace));
+        crate::write_raw_frame(&[1; 10]);
+        // As with a level filtered out with `DEFMT_LOG`, no frame is started in between.
+        let started = crate::frames_started();
+        flush_logged(Level::Error, started);
+        assert!(!CONTROLLER.needs_flush());
+    }
+
+    #[test]
+    fn error_macro_flushes_its_frame() {
+        let _lock = host::lock();
+        set_flush_level(Some(Level::Error));
+        crate::error!("flushed");
+        assert!(CONTROLLER.needs_flush());
+    }
+}
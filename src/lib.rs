@@ -1,41 +1,234 @@
 //! `defmt` logger and USB transport layer.
 
 #![no_std]
+// With the `disabled` feature, the encoder, the buffers and the logger task are not
+// compiled in, which leaves the code that drives them, and what is passed to it, unused.
+#![cfg_attr(
+    feature = "disabled",
+    allow(dead_code, unused_imports, unused_mut, unused_variables)
+)]
 
+// Sequence number headers and channel tags are delimited like rzcobs frames, which
+// the raw encoding does not use, and the history relies on the delimiters to find the
+// first whole frame.
+#[cfg(all(
+    feature = "encoding-raw",
+    any(
+        feature = "sequence-numbers",
+        feature = "channels",
+        feature = "history"
+    )
+))]
+compile_error!(
+    "The `encoding-raw` feature cannot be used with `sequence-numbers`, `channels` or `history`."
+);
+
+pub mod blob;
+#[cfg(all(feature = "panic-flush", not(feature = "disabled")))]
+mod blocking;
+mod boot;
 mod buffer;
+#[cfg(feature = "channels")]
+pub mod channel;
+mod clock;
+#[cfg(feature = "compression")]
+pub mod compress;
+mod config;
 mod controller;
+mod handle;
+pub mod handshake;
+#[cfg(feature = "history")]
+pub mod history;
+mod interrupt;
+mod level;
+#[cfg(feature = "custom-lock")]
+mod lock;
+#[cfg(not(feature = "compression"))]
+mod packets;
+#[cfg(feature = "semihosting-mirror")]
+mod semihosting;
+#[cfg(feature = "sequence-numbers")]
+pub mod sequence;
+#[cfg(feature = "metrics")]
+mod stats;
+mod suspend;
 mod task;
+#[cfg(any(test, feature = "testing"))]
+#[cfg_attr(not(feature = "testing"), allow(dead_code))]
+mod testing;
+pub mod text;
+mod transfer;
+mod transport;
+#[cfg(feature = "uart")]
+mod uart;
 
+#[cfg(not(feature = "disabled"))]
 use core::{
     cell::UnsafeCell,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
 };
 
-pub use task::{logger, run};
+pub use blob::log_blob;
+pub use boot::BOOT_BUFFER_SIZE;
+#[cfg(feature = "channels")]
+pub use channel::ChannelLogger;
+pub use clock::{Clock, EmbassyClock};
+pub use config::{LoggerConfig, Sizes};
+pub use controller::{
+    buffer_size_for_budget, buffer_usage, dropped_bytes, dropped_frames, flush_errors,
+    frames_started, is_connected, peak_buffer_usage, request_flush, reset_peak_buffer_usage,
+    take_dropped_bytes, take_dropped_frames, wait_connected, wait_disconnected, wait_for_drain,
+    LongFramePolicy, Mode, OverflowPolicy, StartupPolicy, BUFFER_RAM, TRUNCATION_MARKER,
+};
+pub use handle::LoggerHandle;
+#[cfg(feature = "history")]
+pub use history::add_history_handler;
+pub use interrupt::{on_usb_interrupt, PollError, PollTransport};
+pub use level::{flush_logged, Level};
+#[cfg(feature = "custom-lock")]
+pub use lock::LoggerLock;
+#[cfg(feature = "metrics")]
+pub use stats::{frame_size_histogram, stats, LoggerStats, FRAME_SIZE_BOUNDS};
+pub use suspend::{add_suspend_handler, on_suspend_change};
+pub use task::{
+    add_logger_class, flush_all, flush_once, logger, logger_until, logger_with_clock,
+    logger_with_fallback, logger_with_flush_config, logger_with_tee, run, run_dual, run_until,
+    run_with_buffers, run_with_control_handler, run_with_flush_config, run_with_receiver,
+    run_with_sizes, ControlSignals, FlushConfig,
+};
+#[cfg(feature = "testing")]
+pub use testing::{MemoryTransport, VirtualClock};
+pub use text::TextWriter;
+pub use transport::{
+    add_bulk_logger_class, add_bulk_logger_class_with, BulkTransport, LogTransport, TransportError,
+};
+#[cfg(feature = "uart")]
+pub use uart::{run_uart, UartTransport};
 
+#[cfg(not(feature = "disabled"))]
 static USB_ENCODER: UsbEncoder = UsbEncoder::new();
 
+// Timestamp log frames with the embassy-time clock, in microseconds since boot.
+//
+// defmt evaluates the timestamp as the frame is logged, and encodes it at the start of
+// the frame, so it records when the frame was produced however long it stays buffered.
+// Only one timestamp may be defined in a program, so this is opt-in.
+#[cfg(feature = "timestamp")]
+defmt::timestamp!("{=u64:us}", embassy_time::Instant::now().as_micros());
+
+/// State needed to release the lock held for a frame.
+#[cfg(not(any(feature = "custom-lock", feature = "disabled")))]
+type RestoreState = critical_section::RestoreState;
+
+/// Token needed to release the lock held for a frame.
+#[cfg(all(feature = "custom-lock", not(feature = "disabled")))]
+type RestoreState = usize;
+
+/// Takes the critical section held for a frame.
+///
+/// # Safety
+///
+/// Must be paired with a call to `release_lock`.
+#[cfg(not(any(feature = "custom-lock", feature = "disabled")))]
+#[inline]
+unsafe fn acquire_lock() -> RestoreState {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { critical_section::acquire() }
+}
+
+/// Releases the critical section held for a frame.
+///
+/// # Safety
+///
+/// `restore_state` must come from the most recent `acquire_lock` not yet released.
+#[cfg(not(any(feature = "custom-lock", feature = "disabled")))]
+#[inline]
+unsafe fn release_lock(restore_state: RestoreState) {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { critical_section::release(restore_state) }
+}
+
+/// Takes the custom lock held for a frame.
+///
+/// # Safety
+///
+/// Must be paired with a call to `release_lock`.
+#[cfg(all(feature = "custom-lock", not(feature = "disabled")))]
+#[inline]
+unsafe fn acquire_lock() -> RestoreState {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { lock::acquire() }
+}
+
+/// Releases the custom lock held for a frame.
+///
+/// # Safety
+///
+/// `restore_state` must come from the most recent `acquire_lock` not yet released.
+#[cfg(all(feature = "custom-lock", not(feature = "disabled")))]
+#[inline]
+unsafe fn release_lock(restore_state: RestoreState) {
+    // SAFETY: Guaranteed by the caller.
+    unsafe { lock::release(restore_state) }
+}
+
+/// Runs `f` holding the lock held for frames, the critical section or the custom lock.
+///
+/// The lock is released even if `f` panics, as with `critical_section::with`.
+#[cfg(not(feature = "disabled"))]
+#[inline]
+fn with_lock<R>(f: impl FnOnce() -> R) -> R {
+    struct Guard(RestoreState);
+
+    impl Drop for Guard {
+        #[inline]
+        fn drop(&mut self) {
+            // SAFETY: The state comes from the `acquire_lock` below, the most recent one.
+            unsafe { release_lock(self.0) }
+        }
+    }
+
+    // SAFETY: The guard releases the lock once `f` returns or panics.
+    let _guard = Guard(unsafe { acquire_lock() });
+    f()
+}
+
+#[cfg(not(feature = "disabled"))]
 struct UsbEncoder {
     /// A boolean lock
     ///
     /// Is `true` when `acquire` has been called and we have exclusive access to the
     /// rest of this struct.
     taken: AtomicBool,
+    /// Number of re-entrant frames currently being dropped
+    ///
+    /// Only used with the `drop-reentrant-frames` feature.
+    nested: AtomicUsize,
     /// Critical section restore state
     ///
-    /// Needed to exit a critical section.
-    restore: UnsafeCell<critical_section::RestoreState>,
+    /// Needed to exit a critical section, or release the custom lock.
+    restore: UnsafeCell<RestoreState>,
     /// A defmt Encoder for encoding frames
     encoder: UnsafeCell<defmt::Encoder>,
 }
 
+// SAFETY: The cells are only accessed between `acquire` and `release`, inside the
+// critical section. On multi-core targets this relies on the `critical-section`
+// implementation excluding the other cores as well, as the multi-core implementations
+// of the RP2040 HALs do with a hardware spinlock.
+#[cfg(not(feature = "disabled"))]
 unsafe impl Sync for UsbEncoder {}
 
+#[cfg(not(feature = "disabled"))]
 impl UsbEncoder {
     const fn new() -> Self {
         Self {
             taken: AtomicBool::new(false),
+            nested: AtomicUsize::new(0),
+            #[cfg(not(feature = "custom-lock"))]
             restore: UnsafeCell::new(critical_section::RestoreState::invalid()),
+            #[cfg(feature = "custom-lock")]
+            restore: UnsafeCell::new(0),
             encoder: UnsafeCell::new(defmt::Encoder::new()),
         }
     }
@@ -44,19 +237,50 @@ impl UsbEncoder {
     ///
     /// This acquires a critical section and begins a defmt frame.
     ///
+    /// The critical section is provided by the `critical-section` crate, which is how
+    /// the locking strategy is chosen: the application (usually through its HAL or
+    /// `cortex-m`) supplies the implementation. On single-core Cortex-M targets this
+    /// typically disables all interrupts; an implementation that masks interrupts
+    /// with BASEPRI instead leaves higher-priority interrupts running, as long as they
+    /// never log. The section is held until `release`, so interrupts are delayed for
+    /// the whole of formatting and encoding the frame, in addition to the copy into the
+    /// buffers done by `Controller::write`.
+    ///
+    /// With the `custom-lock` feature, the lock set with [`set_logger_lock!`] is held
+    /// instead of the critical section.
+    ///
     /// # Panics
     ///
-    /// This will panic if you attempt to acquire the logger re-entrantly.
+    /// This will panic if you attempt to acquire the logger re-entrantly, unless the
+    /// `drop-reentrant-frames` feature is enabled, in which case the re-entrant frame is
+    /// silently dropped.
     fn acquire(&self) {
+        // In lossless mode, wait for room for the frame before taking the critical
+        // section, so that the logger task can run. If the logger is already taken, this
+        // is a re-entrant call made from within the outer frame's critical section, where
+        // waiting could never succeed.
+        if !self.taken.load(Ordering::Relaxed) {
+            controller::CONTROLLER.wait_for_space();
+        }
+
         // Get in a critical section.
         //
         // SAFETY: Must be paired with a call to release, as it is in the contract of
         // the Logger trait.
-        let restore_state = unsafe { critical_section::acquire() };
+        let restore_state = unsafe { acquire_lock() };
 
         // Fail if the logger is acquired re-entrantly, to avoid two places with
         // mutable access to the logger state.
         if self.taken.load(Ordering::Relaxed) {
+            if cfg!(feature = "drop-reentrant-frames") {
+                // Drop the inner frame instead. We are still inside the critical section
+                // taken by the outer frame, so release the one just taken right away.
+                self.nested
+                    .store(self.nested.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                // SAFETY: This is the most recently acquired critical section.
+                unsafe { release_lock(restore_state) };
+                return;
+            }
             panic!("defmt logger taken reentrantly");
         }
 
@@ -70,6 +294,7 @@ impl UsbEncoder {
             self.restore.get().write(restore_state);
 
             // Start the defmt frame.
+            controller::CONTROLLER.start_frame();
             let encoder = &mut *self.encoder.get();
             encoder.start_frame(Self::inner);
         }
@@ -88,25 +313,48 @@ impl UsbEncoder {
             panic!("defmt release outside of critical section.")
         }
 
+        // Finish a dropped re-entrant frame, whose critical section was already released.
+        if self.is_nested() {
+            self.nested
+                .store(self.nested.load(Ordering::Relaxed) - 1, Ordering::Relaxed);
+            return;
+        }
+
         // SAFETY: Accessing the UnsafeCells and finally releasing the critical section
         // is OK because we know we are in a critical section at this point.
         unsafe {
             let encoder = &mut *self.encoder.get();
             encoder.end_frame(Self::inner);
+            controller::CONTROLLER.end_frame();
 
             let restore_state = self.restore.get().read();
             self.taken.store(false, Ordering::Relaxed);
-            critical_section::release(restore_state);
+            release_lock(restore_state);
         }
     }
 
     /// Flush the current buffer.
     ///
+    /// With the `panic-flush` feature, this also sends the buffers over USB before
+    /// returning, so that frames are not lost if the logger task never runs again.
+    ///
     /// # Safety
     ///
     /// Must be called after calling `acquire` and before calling `release`.
     unsafe fn flush(&self) {
-        controller::CONTROLLER.swap()
+        // Ignore a dropped re-entrant frame.
+        if self.is_nested() {
+            return;
+        }
+
+        #[cfg_attr(not(feature = "panic-flush"), allow(unused_variables))]
+        let swapped = controller::CONTROLLER.swap();
+
+        // Nothing is sent while the logger is disabled.
+        #[cfg(feature = "panic-flush")]
+        if swapped {
+            blocking::flush();
+        }
     }
 
     /// Write bytes to the defmt encoder.
@@ -115,10 +363,88 @@ impl UsbEncoder {
     ///
     /// Must be called after calling `acquire` and before calling `release`.
     unsafe fn write(&self, bytes: &[u8]) {
+        // Ignore a dropped re-entrant frame.
+        if self.is_nested() {
+            return;
+        }
+
         let encoder = &mut *self.encoder.get();
         encoder.write(bytes, Self::inner)
     }
 
+    /// Write a complete, already encoded frame to the buffers.
+    fn write_raw(&self, frame: &[u8]) {
+        self.write_frame(|| {
+            // SAFETY: `write_frame` calls this within a critical section, between the
+            // start and end of a frame.
+            unsafe { controller::CONTROLLER.write(frame) }
+        });
+    }
+
+    /// Write a frame whose bytes are written by `f`, bypassing the defmt encoder.
+    ///
+    /// This takes its own lock, like `acquire`, and holds the logger while
+    /// `f` runs, so that the bytes are kept whole or dropped whole as a single frame of
+    /// the controller. Returns `None` without calling `f` if the frame is dropped.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if called while a defmt frame is being logged, or if `f` logs,
+    /// unless the `drop-reentrant-frames` feature is enabled, in which case the inner
+    /// frame is silently dropped.
+    pub(crate) fn write_frame<R>(&self, f: impl FnOnce() -> R) -> Option<R> {
+        // In lossless mode, wait for room for the frame, as `acquire` does.
+        if !self.taken.load(Ordering::Relaxed) {
+            controller::CONTROLLER.wait_for_space();
+        }
+
+        with_lock(|| {
+            // The controller's frame state belongs to the defmt frame being logged.
+            if self.taken.load(Ordering::Relaxed) {
+                if cfg!(feature = "drop-reentrant-frames") {
+                    return None;
+                }
+                panic!("defmt logger taken reentrantly");
+            }
+            self.taken.store(true, Ordering::Relaxed);
+
+            // SAFETY: We are in a critical section, and no defmt frame is in progress.
+            unsafe { controller::CONTROLLER.start_frame() };
+            let result = f();
+            // SAFETY: As above.
+            unsafe { controller::CONTROLLER.end_frame() };
+
+            self.taken.store(false, Ordering::Relaxed);
+            Some(result)
+        })
+    }
+
+    /// Forcibly release the logger after a frame was abandoned, see [`reset_encoder`].
+    ///
+    /// # Safety
+    ///
+    /// The frame being logged, if any, must never be resumed.
+    unsafe fn reset(&self) {
+        with_lock(|| {
+            if self.taken.load(Ordering::Relaxed) && !self.is_nested() {
+                // SAFETY: We are in a critical section, and the abandoned frame is never
+                // resumed, as guaranteed by the caller.
+                unsafe { controller::CONTROLLER.abort_frame() };
+            }
+            // SAFETY: We are in a critical section, and nothing else uses the encoder
+            // while the logger is not taken.
+            unsafe { self.encoder.get().write(defmt::Encoder::new()) };
+            self.nested.store(0, Ordering::Relaxed);
+            self.taken.store(false, Ordering::Relaxed);
+        });
+    }
+
+    /// Returns `true` if a re-entrant frame is being dropped.
+    #[inline]
+    fn is_nested(&self) -> bool {
+        self.nested.load(Ordering::Relaxed) > 0
+    }
+
     fn inner(bytes: &[u8]) {
         // SAFETY: Always called from within a critical section by the defmt logger.
         unsafe {
@@ -128,23 +454,248 @@ impl UsbEncoder {
 }
 
 /// The logger implementation.
+///
+/// With the `disabled` feature, every method is empty, and the encoder, buffers and
+/// logger task are not compiled in, so that logging compiles to nothing.
 #[defmt::global_logger]
 pub struct USBLogger;
 
 unsafe impl defmt::Logger for USBLogger {
     fn acquire() {
+        #[cfg(not(feature = "disabled"))]
         USB_ENCODER.acquire();
     }
 
     unsafe fn release() {
+        #[cfg(not(feature = "disabled"))]
         USB_ENCODER.release();
     }
 
     unsafe fn flush() {
+        #[cfg(not(feature = "disabled"))]
         USB_ENCODER.flush();
     }
 
     unsafe fn write(bytes: &[u8]) {
+        #[cfg(not(feature = "disabled"))]
         USB_ENCODER.write(bytes);
     }
 }
+
+/// Writes a complete, already encoded defmt frame to the logger's buffers.
+///
+/// This allows frames encoded elsewhere, for instance relayed from another chip over
+/// UART, to be sent over the same USB port as the local logs without being encoded
+/// again. The bytes are written as they are, bypassing the defmt encoder, and are
+/// handled like any other frame: they are kept whole, and dropped according to the
+/// [`Mode`] and [`OverflowPolicy`] if the buffers are full. A frame larger than a
+/// buffer is always dropped.
+///
+/// The caller is responsible for the framing of `frame`: it must be exactly one frame
+/// encoded the way the host expects, which with the default rzCOBS encoding means
+/// ending with its `0x00` delimiter. The frame must also come from a program whose
+/// ELF file the host can decode it with.
+///
+/// # Panics
+///
+/// Panics if called from within a defmt log call, for instance from a `Format`
+/// implementation, unless the `drop-reentrant-frames` feature is enabled, in which
+/// case the frame is dropped.
+pub fn write_raw_frame(frame: &[u8]) {
+    #[cfg(not(feature = "disabled"))]
+    USB_ENCODER.write_raw(frame);
+}
+
+/// Forcibly releases the logger, so that logging works again after a frame was
+/// abandoned midway.
+///
+/// If a fault interrupts a log call, the logger stays acquired and every further log
+/// call panics as re-entrant. Calling this from the fault or panic handler, before
+/// logging the final messages, discards the partial frame from the buffers and resets
+/// the encoder so that new frames can be logged.
+///
+/// The critical section taken by the abandoned frame is never released, so on most
+/// single-core targets interrupts stay disabled afterwards. This is only suitable for a
+/// handler that does not return to the interrupted code, and that flushes with
+/// `defmt::flush()` (with the `panic-flush` feature) rather than relying on the logger
+/// task.
+///
+/// # Safety
+///
+/// The interrupted log call must never be resumed: it would write into the new state
+/// and release a critical section it no longer holds. This must not be called while a
+/// log call is in progress that will complete normally, for instance from an interrupt
+/// handler that logs.
+pub unsafe fn reset_encoder() {
+    #[cfg(not(feature = "disabled"))]
+    // SAFETY: Guaranteed by the caller.
+    unsafe {
+        USB_ENCODER.reset()
+    };
+}
+
+/// Sends the buffered frames over USB from a panic or fault handler, with the
+/// `panic-flush` feature.
+///
+/// The logger task never runs again after a panic, so frames still buffered, such as
+/// the panic message, are otherwise never sent. This marks the active buffer as
+/// flushing, then busy-polls the endpoint until all the buffers are sent or 500ms have
+/// passed, so it returns even if the host is not reading. As with `defmt::flush()`,
+/// nothing is sent if the panic interrupted a transfer in progress or the device is
+/// not connected. If the panic interrupted a log call, the frame it was logging is not
+/// sent; call [`reset_encoder`] first to discard it and send the rest.
+///
+/// A panic handler logging the panic message then sending it:
+///
+/// ```ignore
+/// #[panic_handler]
+/// fn panic(info: &core::panic::PanicInfo) -> ! {
+///     defmt::error!("{}", defmt::Display2Format(info));
+///     defmtusb::panic_flush();
+///     cortex_m::asm::udf()
+/// }
+/// ```
+///
+/// `panic-probe` provides its own panic handler, which logs the message with its
+/// `print-defmt` feature and then raises a HardFault, so with it call this from the
+/// HardFault handler instead:
+///
+/// ```ignore
+/// #[cortex_m_rt::exception]
+/// unsafe fn HardFault(_frame: &cortex_m_rt::ExceptionFrame) -> ! {
+///     defmtusb::panic_flush();
+///     loop {}
+/// }
+/// ```
+#[cfg(feature = "panic-flush")]
+pub fn panic_flush() {
+    // Never send part of a frame being logged.
+    #[cfg(not(feature = "disabled"))]
+    {
+        if !frame_in_progress() {
+            controller::CONTROLLER.flush_current();
+        }
+        blocking::flush();
+    }
+}
+
+/// Returns `true` if a frame is being logged, that is if the logger has been acquired
+/// and not yet released.
+///
+/// Outside of the logger, this can only read `true` from code that runs while a log
+/// call holds its critical section: a fault or panic handler that interrupted the log
+/// call, or another core on multi-core targets. In a fault handler, `true` means the
+/// frame was abandoned midway, and the logger must be released with [`reset_encoder`]
+/// before logging again.
+///
+/// An abandoned frame is never sent partially. Frames are only ever sent whole, as the
+/// part of a frame written to a buffer stays in it until the frame ends, and
+/// `reset_encoder` removes it. Partial frames reaching the host come instead from a
+/// transfer cut short by a disconnect, which the host decoder recovers from at the next
+/// `0x00` delimiter.
+pub fn frame_in_progress() -> bool {
+    #[cfg(feature = "disabled")]
+    return false;
+    #[cfg(not(feature = "disabled"))]
+    USB_ENCODER.taken.load(Ordering::Relaxed)
+}
+
+// Channel tags add bytes to the frames, which the tests do not expect.
+#[cfg(all(test, not(any(feature = "channels", feature = "disabled"))))]
+mod tests {
+    extern crate std;
+
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::testing::host;
+
+    /// Returns the frame of `bytes` as encoded by defmt.
+    fn encoded(bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = defmt::Encoder::new();
+        encoder.start_frame(|b| out.extend_from_slice(b));
+        encoder.write(bytes, |b| out.extend_from_slice(b));
+        encoder.end_frame(|b| out.extend_from_slice(b));
+        out
+    }
+
+    /// Returns the bytes buffered by the logger, emptying its buffers.
+    fn buffered() -> Vec<u8> {
+        controller::CONTROLLER.flush_current();
+        let mut out = Vec::new();
+        let _ = embassy_futures::block_on(controller::CONTROLLER.flush(
+            async |contents: &[&[u8]; controller::BUFFERCOUNT]| {
+                out.extend(contents.concat());
+                Ok::<(), ()>(())
+            },
+        ));
+        out
+    }
+
+    #[cfg(feature = "custom-lock")]
+    #[test]
+    fn raw_frames_and_resets_take_the_custom_lock() {
+        let _lock = host::lock();
+        let acquired = || host::TEST_LOCK_ACQUIRED.load(Ordering::Relaxed);
+        let before = acquired();
+        write_raw_frame(&[1, 2, 3]);
+        assert_eq!(acquired(), before + 1);
+        // SAFETY: No frame is being logged.
+        unsafe { reset_encoder() };
+        assert_eq!(acquired(), before + 2);
+    }
+
+    #[cfg(feature = "drop-reentrant-frames")]
+    #[test]
+    fn reentrant_frame_is_dropped() {
+        let _lock = host::lock();
+        USB_ENCODER.acquire();
+        // SAFETY: The logger was acquired above, and each acquire is released once.
+        unsafe {
+            USB_ENCODER.write(b"outer ");
+            USB_ENCODER.acquire();
+            USB_ENCODER.write(b"inner");
+            USB_ENCODER.flush();
+            USB_ENCODER.release();
+            assert!(frame_in_progress());
+            USB_ENCODER.write(b"frame");
+            USB_ENCODER.release();
+        }
+        assert!(!frame_in_progress());
+        assert_eq!(USB_ENCODER.write_frame(|| ()), Some(()));
+        assert_eq!(buffered(), encoded(b"outer frame"));
+    }
+
+    #[cfg(feature = "drop-reentrant-frames")]
+    #[test]
+    fn reentrant_raw_frame_is_dropped() {
+        let _lock = host::lock();
+        USB_ENCODER.acquire();
+        assert_eq!(USB_ENCODER.write_frame(|| ()), None);
+        // SAFETY: The logger was acquired above.
+        unsafe {
+            USB_ENCODER.write(b"outer");
+            USB_ENCODER.release();
+        }
+        assert_eq!(buffered(), encoded(b"outer"));
+    }
+
+    #[cfg(not(feature = "drop-reentrant-frames"))]
+    #[test]
+    fn reentrant_frame_panics() {
+        let _lock = host::lock();
+        USB_ENCODER.acquire();
+        // SAFETY: The logger was acquired above.
+        unsafe { USB_ENCODER.write(b"outer") };
+        let inner = std::panic::catch_unwind(|| USB_ENCODER.acquire());
+        let raw = std::panic::catch_unwind(|| USB_ENCODER.write_frame(|| ()));
+        // The outer frame can still be finished.
+        // SAFETY: The logger was acquired above, and the inner acquires never completed.
+        unsafe { USB_ENCODER.release() };
+        assert!(inner.is_err());
+        assert!(raw.is_err());
+        assert!(!frame_in_progress());
+        assert_eq!(buffered(), encoded(b"outer"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Custom lock held by the logger for each frame, enabled with the `custom-lock`
+//! feature.
+
+/// A lock held by the logger from the start to the end of each frame, in place of the
+/// global critical section.
+///
+/// The logger normally takes a `critical-section` critical section for each frame,
+/// which on most targets masks every interrupt. A custom lock can mask less, for
+/// instance by raising BASEPRI only to the highest priority that logs, so that more
+/// urgent interrupts keep running while logging. The lock is installed with
+/// [`set_logger_lock!`](crate::set_logger_lock).
+///
+/// # Safety
+///
+/// While held, the lock must prevent any other code that logs (or calls other functions
+/// of this crate) from running, on every core, and must be released in the reverse
+/// order it was acquired. Code that may preempt the lock, such as interrupts above the
+/// masked priority, must never log. The crate's own critical sections must also
+/// exclude the lock's holder, which is the case when they mask at least as much.
+pub unsafe trait LoggerLock {
+    /// Acquires the lock, returning a token passed to the matching `release`.
+    ///
+    /// # Safety
+    ///
+    /// Must be paired with a call to `release`.
+    unsafe fn acquire() -> usize;
+
+    /// Releases the lock.
+    ///
+    /// # Safety
+    ///
+    /// `token` must be the value returned by the matching call to `acquire`, which must
+    /// be the most recent one not yet released.
+    unsafe fn release(token: usize);
+}
+
+/// Sets the lock held by the logger for each frame, with the `custom-lock` feature.
+///
+/// This must be used exactly once in the program, with a type implementing
+/// [`LoggerLock`]:
+///
+/// ```ignore
+/// struct BasepriLock;
+///
+/// unsafe impl defmtusb::LoggerLock for BasepriLock {
+///     unsafe fn acquire() -> usize {
+///         let previous = cortex_m::register::basepri::read();
+///         cortex_m::register::basepri_max::write(LOGGING_PRIORITY);
+///         previous as usize
+///     }
+///
+///     unsafe fn release(token: usize) {
+///         cortex_m::register::basepri::write(token as u8);
+///     }
+/// }
+///
+/// defmtusb::set_logger_lock!(BasepriLock);
+/// ```
+#[macro_export]
+macro_rules! set_logger_lock {
+    ($lock:ty) => {
+        #[no_mangle]
+        unsafe fn _defmtusb_lock_acquire() -> usize {
+            <$lock as $crate::LoggerLock>::acquire()
+        }
+
+        #[no_mangle]
+        unsafe fn _defmtusb_lock_release(token: usize) {
+            <$lock as $crate::LoggerLock>::release(token)
+        }
+    };
+}
+
+extern "Rust" {
+    fn _defmtusb_lock_acquire() -> usize;
+    fn _defmtusb_lock_release(token: usize);
+}
+
+/// Acquires the lock set with [`set_logger_lock!`](crate::set_logger_lock).
+///
+/// # Safety
+///
+/// Must be paired with a call to `release`.
+#[inline]
+pub(crate) unsafe fn acquire() -> usize {
+    // SAFETY: The function is defined by `set_logger_lock!`, and the caller upholds the
+    // contract of `LoggerLock::acquire`.
+    unsafe { _defmtusb_lock_acquire() }
+}
+
+/// Releases the lock set with [`set_logger_lock!`](crate::set_logger_lock).
+///
+/// # Safety
+///
+/// As [`LoggerLock::release`].
+#[inline]
+pub(crate) unsafe fn release(token: usize) {
+    // SAFETY: The function is defined by `set_logger_lock!`, and the caller upholds the
+    // contract of `LoggerLock::release`.
+    unsafe { _defmtusb_lock_release(token) }
+}
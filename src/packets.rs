@@ -0,0 +1,82 @@
+//! Splitting of buffered frames into packets.
+
+use crate::buffer::BUFFERSIZE;
+
+/// Size of the scratch buffer that packets spanning two buffers are built in.
+///
+/// This holds a full-speed packet, or a 512-byte high-speed packet when the buffers are
+/// large enough for one.
+const SCRATCH_SIZE: usize = if BUFFERSIZE >= 512 { 512 } else { 64 };
+
+/// Splits the contents of several buffers into packets, as a single stream.
+///
+/// Packets are taken directly from the buffers where possible. When the end of a
+/// buffer does not fill a packet, it is copied along with the start of the next
+/// buffer into a scratch packet, so that the host receives full packets instead of a
+/// short packet per buffer. Packets larger than the scratch buffer are not coalesced.
+pub(crate) struct Packets<'a> {
+    /// Remaining bytes of the buffer being split.
+    current: &'a [u8],
+    /// Buffers not yet started.
+    rest: &'a [&'a [u8]],
+    /// Maximum size of each packet.
+    packet_size: usize,
+    /// Buffer that packets spanning two buffers are built in.
+    scratch: [u8; SCRATCH_SIZE],
+}
+
+impl<'a> Packets<'a> {
+    /// Split the concatenation of `buffers` into packets of at most `packet_size` bytes.
+    pub(crate) fn new(buffers: &'a [&'a [u8]], packet_size: usize) -> Self {
+        Self {
+            current: &[],
+            rest: buffers,
+            packet_size,
+            scratch: [0; SCRATCH_SIZE],
+        }
+    }
+
+    /// Returns the next packet, or `None` once all the buffers have been split.
+    pub(crate) fn next_packet(&mut self) -> Option<&[u8]> {
+        self.advance();
+        if self.current.is_empty() {
+            return None;
+        }
+
+        let more = self.rest.iter().any(|bytes| !bytes.is_empty());
+        if self.current.len() >= self.packet_size || self.packet_size > SCRATCH_SIZE || !more {
+            // Send straight from the buffer.
+            let (packet, remaining) = self
+                .current
+                .split_at(self.current.len().min(self.packet_size));
+            self.current = remaining;
+            return Some(packet);
+        }
+
+        // Copy the end of this buffer and the start of the following ones into a
+        // single packet.
+        let mut len = 0;
+        while len < self.packet_size {
+            self.advance();
+            if self.current.is_empty() {
+                break;
+            }
+            let n = self.current.len().min(self.packet_size - len);
+            self.scratch[len..len + n].copy_from_slice(&self.current[..n]);
+            self.current = &self.current[n..];
+            len += n;
+        }
+        Some(&self.scratch[..len])
+    }
+
+    /// Move on to the next non-empty buffer once the current one is exhausted.
+    fn advance(&mut self) {
+        while self.current.is_empty() {
+            let Some((&next, rest)) = self.rest.split_first() else {
+                return;
+            };
+            self.current = next;
+            self.rest = rest;
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Mirroring of log frames to the debugger over semihosting, enabled with the
+//! `semihosting-mirror` feature.
+//!
+//! Every byte written to the buffers is also written to the semihosting console, so
+//! that frames logged during bring-up, before the USB device is connected, can be read
+//! through the debug probe even if they are dropped from the buffers. The bytes are
+//! the defmt-encoded frames, which the host must decode as it would the USB stream.
+//!
+//! A semihosting call without a debugger attached raises a HardFault, so the mirror
+//! first checks that a debugger is attached, with the C_DEBUGEN bit of the Debug
+//! Halting Control and Status Register. The core can only read that register on
+//! ARMv7-M and ARMv8-M Mainline targets, so the mirror does nothing on other targets,
+//! including ARMv6-M (Cortex-M0 and M0+).
+//!
+//! Each semihosting call halts the core until the debugger has handled it, which takes
+//! milliseconds, from within the logger's critical section. The mirror is only meant
+//! for development.
+
+/// Write defmt-encoded bytes to the semihosting console, if a debugger is attached.
+///
+/// # Safety
+///
+/// Must be called from within a critical section.
+#[cfg(all(target_arch = "arm", target_feature = "mclass", target_feature = "v7"))]
+#[inline]
+pub(crate) unsafe fn mirror(bytes: &[u8]) {
+    use core::sync::atomic::Ordering;
+
+    use portable_atomic::AtomicIsize;
+
+    /// Address of the Debug Halting Control and Status Register.
+    const DHCSR: *const u32 = 0xE000_EDF0 as *const u32;
+    /// Bit of DHCSR set while a debugger is attached.
+    const C_DEBUGEN: u32 = 1 << 0;
+    /// Semihosting operation opening a file.
+    const SYS_OPEN: usize = 0x01;
+    /// Semihosting operation writing to a file.
+    const SYS_WRITE: usize = 0x05;
+    /// Mode of `SYS_OPEN` for writing, as `fopen`'s `"w"`.
+    const MODE_WRITE: usize = 4;
+    /// Value of `CONSOLE` before the console is opened.
+    const NOT_OPEN: isize = -1;
+
+    /// Handle of the semihosting console, once opened.
+    static CONSOLE: AtomicIsize = AtomicIsize::new(NOT_OPEN);
+
+    /// Perform a semihosting operation.
+    ///
+    /// # Safety
+    ///
+    /// A debugger must be attached, and `args` must point to the operation's arguments.
+    unsafe fn call(op: usize, args: &[usize]) -> isize {
+        let result: usize;
+        // SAFETY: Guaranteed by the caller. The debugger only reads the arguments.
+        unsafe {
+            core::arch::asm!(
+                "bkpt #0xab",
+                inout("r0") op => result,
+                in("r1") args.as_ptr(),
+                options(nostack, preserves_flags),
+            );
+        }
+        result as isize
+    }
+
+    // SAFETY: DHCSR is always readable by the core on these targets.
+    if unsafe { core::ptr::read_volatile(DHCSR) } & C_DEBUGEN == 0 {
+        return;
+    }
+
+    // The caller holds a critical section, so a plain load and store is enough.
+    let mut console = CONSOLE.load(Ordering::Relaxed);
+    if console == NOT_OPEN {
+        // The special file name `:tt` is the debugger's console.
+        let name = b":tt\0";
+        // SAFETY: A debugger is attached, and the arguments are those of SYS_OPEN.
+        console = unsafe { call(SYS_OPEN, &[name.as_ptr() as usize, MODE_WRITE, 3]) };
+        if console == NOT_OPEN {
+            return;
+        }
+        CONSOLE.store(console, Ordering::Relaxed);
+    }
+    // SAFETY: A debugger is attached, and the arguments are those of SYS_WRITE.
+    unsafe {
+        call(
+            SYS_WRITE,
+            &[console as usize, bytes.as_ptr() as usize, bytes.len()],
+        )
+    };
+}
+
+/// Does nothing, as the core cannot tell whether a debugger is attached on this target.
+///
+/// # Safety
+///
+/// Must be called from within a critical section.
+#[cfg(not(all(target_arch = "arm", target_feature = "mclass", target_feature = "v7")))]
+#[inline]
+pub(crate) unsafe fn mirror(_bytes: &[u8]) {}
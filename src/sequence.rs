@@ -0,0 +1,152 @@
+//! Sequence numbers prefixed to each sent buffer.
+//!
+//! With the `sequence-numbers` feature, each buffer sent to the host is preceded by a
+//! header carrying a sequence number, which increases by one for each buffer. A gap
+//! in the sequence numbers seen by the host means a buffer was lost, for instance
+//! because sending it failed.
+//!
+//! The header is [`HEADER_LEN`] bytes long:
+//!
+//! - `0x00`, ending any partial frame before it.
+//! - The three [`MARKER`] bytes, `SEQ`.
+//! - The sequence number, in four bytes of seven bits each, least significant first,
+//!   with the high bit of each byte set. Sequence numbers therefore wrap at `2^28`.
+//! - `0x00`, so that the header is delimited like a defmt frame.
+//!
+//! The header never contains a `0x00` byte other than its delimiters, so it does not
+//! disturb the decoding of the defmt frames around it. A decoder that is not aware of
+//! the header sees it as a malformed frame, so the host should remove headers before
+//! decoding. In Python:
+//!
+//! ```python
+//! HEADER = re.compile(rb"\x00SEQ([\x80-\xff]{4})\x00")
+//!
+//! def sequence(match):
+//!     return sum((b & 0x7F) << (7 * i) for i, b in enumerate(match.group(1)))
+//! ```
+//!
+//! With the `compression` feature, headers are added before compression.
+
+use crate::controller::BUFFERCOUNT;
+#[cfg(not(feature = "disabled"))]
+use crate::controller::CONTROLLER;
+
+/// Marker bytes identifying a header.
+pub const MARKER: [u8; 3] = *b"SEQ";
+
+/// Length of a header in bytes.
+pub const HEADER_LEN: usize = 9;
+
+/// Returns the header for the given sequence number.
+fn header(sequence: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0; HEADER_LEN];
+    header[1..4].copy_from_slice(&MARKER);
+    for (i, byte) in header[4..8].iter_mut().enumerate() {
+        *byte = 0x80 | ((sequence >> (7 * i)) & 0x7F) as u8;
+    }
+    header
+}
+
+/// Interleaves the contents of the buffers being sent with their headers.
+///
+/// Each non-empty buffer is given the next sequence number, and its header is written
+/// to the corresponding entry of `headers`.
+#[cfg(not(feature = "disabled"))]
+pub(crate) fn prefix<'a>(
+    buffers: &[&'a [u8]; BUFFERCOUNT],
+    headers: &'a mut [[u8; HEADER_LEN]; BUFFERCOUNT],
+) -> [&'a [u8]; 2 * BUFFERCOUNT] {
+    number(buffers, headers);
+    interleave(buffers, headers)
+}
+
+/// Writes the header of each non-empty buffer to the corresponding entry of `headers`,
+/// giving each the next sequence number.
+#[cfg(not(feature = "disabled"))]
+pub(crate) fn number(
+    buffers: &[&[u8]; BUFFERCOUNT],
+    headers: &mut [[u8; HEADER_LEN]; BUFFERCOUNT],
+) {
+    for (bytes, header) in buffers.iter().zip(headers.iter_mut()) {
+        if !bytes.is_empty() {
+            *header = self::header(CONTROLLER.next_sequence());
+        }
+    }
+}
+
+/// Interleaves the contents of the buffers with the headers written by [`number`].
+pub(crate) fn interleave<'a>(
+    buffers: &[&'a [u8]; BUFFERCOUNT],
+    headers: &'a [[u8; HEADER_LEN]; BUFFERCOUNT],
+) -> [&'a [u8]; 2 * BUFFERCOUNT] {
+    let mut prefixed: [&[u8]; 2 * BUFFERCOUNT] = [&[]; 2 * BUFFERCOUNT];
+    for ((&bytes, header), slots) in buffers
+        .iter()
+        .zip(headers.iter())
+        .zip(prefixed.chunks_exact_mut(2))
+    {
+        if bytes.is_empty() {
+            continue;
+        }
+        slots[0] = header;
+        slots[1] = bytes;
+    }
+    prefixed
+}
+
+#[cfg(all(test, not(feature = "disabled")))]
+mod tests {
+    extern crate std;
+
+    use std::vec;
+
+    use super::*;
+    use crate::{
+        buffer::BUFFERSIZE,
+        testing::{host, MemoryTransport},
+    };
+
+    #[test]
+    fn headers_hold_seven_bits_per_byte() {
+        assert_eq!(header(0), [0, b'S', b'E', b'Q', 0x80, 0x80, 0x80, 0x80, 0]);
+        assert_eq!(
+            header(200),
+            [0, b'S', b'E', b'Q', 0xC8, 0x81, 0x80, 0x80, 0]
+        );
+        assert_eq!(
+            header((1 << 28) - 1),
+            [0, b'S', b'E', b'Q', 0xFF, 0xFF, 0xFF, 0xFF, 0]
+        );
+        assert_eq!(header(1 << 28), header(0));
+    }
+
+    #[test]
+    fn empty_buffers_have_no_header() {
+        let mut buffers: [&[u8]; BUFFERCOUNT] = [&[]; BUFFERCOUNT];
+        buffers[1] = &[1, 2];
+        let mut headers = [[0; HEADER_LEN]; BUFFERCOUNT];
+        headers[1] = header(7);
+        let prefixed = interleave(&buffers, &headers);
+        assert_eq!(prefixed.concat(), [&header(7)[..], &[1, 2]].concat());
+    }
+
+    #[test]
+    fn each_buffer_sent_is_numbered() {
+        let _lock = host::lock();
+        let frames = [vec![1; BUFFERSIZE - 10], vec![2; 20]];
+        for frame in &frames {
+            crate::write_raw_frame(frame);
+        }
+        let mut transport = MemoryTransport::new(64);
+        embassy_futures::block_on(crate::flush_all(&mut transport)).unwrap();
+
+        #[cfg(feature = "compression")]
+        let bytes = host::decompress(&transport.bytes);
+        #[cfg(not(feature = "compression"))]
+        let bytes = transport.bytes.clone();
+        let (sequences, _) = host::strip_headers(&bytes);
+        assert_eq!(sequences.len(), 2);
+        assert_eq!(sequences[1], (sequences[0] + 1) % (1 << 28));
+        assert_eq!(host::decode(&transport.bytes), frames.concat());
+    }
+}
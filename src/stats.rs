@@ -0,0 +1,208 @@
+//! Logger statistics, enabled with the `metrics` feature.
+
+use core::sync::atomic::Ordering;
+
+use portable_atomic::AtomicUsize;
+
+/// Counters updated by the controller.
+pub(super) static COUNTERS: Counters = Counters::new();
+
+/// Upper bounds, exclusive, of the first buckets of [`frame_size_histogram`], in bytes.
+///
+/// The last bucket holds the frames of at least the last bound.
+pub const FRAME_SIZE_BOUNDS: [usize; 4] = [16, 32, 64, 128];
+
+/// Number of buckets of [`frame_size_histogram`].
+const FRAME_SIZE_BUCKETS: usize = FRAME_SIZE_BOUNDS.len() + 1;
+
+/// Returns the number of frames written to the buffers by size: below 16, 32, 64 and
+/// 128 bytes, then 128 bytes and over.
+///
+/// The sizes are of the encoded frames, as stored in the buffers. This shows how many
+/// frames of each size a buffer holds, to choose between more buffers and larger ones.
+/// Frames that were dropped are not counted. The histogram is reset along with the
+/// other statistics.
+pub fn frame_size_histogram() -> [usize; FRAME_SIZE_BUCKETS] {
+    COUNTERS.snapshot().frame_sizes
+}
+
+/// Returns a snapshot of the logger statistics.
+///
+/// The counters are read one at a time, so a snapshot taken while logging may be
+/// slightly inconsistent between fields.
+pub fn stats() -> LoggerStats {
+    COUNTERS.snapshot()
+}
+
+/// Statistics of the logger since startup or the last [`LoggerStats::reset`].
+///
+/// All counters wrap on overflow.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LoggerStats {
+    /// Number of bytes of the frames written whole to the buffers.
+    ///
+    /// This is the encoded length of the frames, after rzcobs encoding, as sent to the
+    /// host. It is counted once per frame, when the frame ends. Frames that are dropped
+    /// or truncated are not counted.
+    pub bytes_written: usize,
+    /// Number of bytes successfully sent to the host.
+    pub bytes_sent: usize,
+    /// Number of frames dropped because no buffer could accept them.
+    pub frames_dropped: usize,
+    /// Number of frames dropped because the next buffer was still waiting to be sent,
+    /// meaning the host is not reading fast enough. Adding buffers or flushing more
+    /// often helps.
+    pub stalls: usize,
+    /// Number of frames dropped because they were larger than a buffer. Larger buffers
+    /// or shorter log messages help.
+    pub oversized_frames: usize,
+    /// Number of times a buffer was marked as flushing and the next one made active.
+    pub swaps: usize,
+    /// Number of buffers discarded because sending them failed.
+    pub flush_errors: usize,
+    /// Number of frames written by size, see [`frame_size_histogram`].
+    pub frame_sizes: [usize; FRAME_SIZE_BUCKETS],
+}
+
+impl LoggerStats {
+    /// Resets all the counters to zero.
+    ///
+    /// This does not affect [`dropped_frames`](crate::dropped_frames), which is
+    /// counted separately.
+    pub fn reset() {
+        Self::take();
+    }
+
+    /// Returns a snapshot of the statistics since the last reset, and resets all the
+    /// counters to zero.
+    ///
+    /// Reading and resetting happen in one critical section, so nothing is missed or
+    /// counted twice between periodic reports.
+    pub fn take() -> Self {
+        critical_section::with(|_| {
+            let stats = COUNTERS.snapshot();
+            COUNTERS.bytes_written.store(0, Ordering::Relaxed);
+            COUNTERS.bytes_sent.store(0, Ordering::Relaxed);
+            COUNTERS.frames_dropped.store(0, Ordering::Relaxed);
+            COUNTERS.stalls.store(0, Ordering::Relaxed);
+            COUNTERS.oversized_frames.store(0, Ordering::Relaxed);
+            COUNTERS.swaps.store(0, Ordering::Relaxed);
+            COUNTERS.flush_errors.store(0, Ordering::Relaxed);
+            for count in &COUNTERS.frame_sizes {
+                count.store(0, Ordering::Relaxed);
+            }
+            stats
+        })
+    }
+}
+
+/// Atomic counters backing [`LoggerStats`].
+///
+/// Like the controller's own counters, these are updated with plain loads and stores
+/// inside a critical section rather than read-modify-write operations, which some
+/// targets can only provide by taking another critical section.
+pub(super) struct Counters {
+    bytes_written: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    frames_dropped: AtomicUsize,
+    stalls: AtomicUsize,
+    oversized_frames: AtomicUsize,
+    swaps: AtomicUsize,
+    flush_errors: AtomicUsize,
+    frame_sizes: [AtomicUsize; FRAME_SIZE_BUCKETS],
+}
+
+impl Counters {
+    /// Static initializer.
+    const fn new() -> Self {
+        Self {
+            bytes_written: AtomicUsize::new(0),
+            bytes_sent: AtomicUsize::new(0),
+            frames_dropped: AtomicUsize::new(0),
+            stalls: AtomicUsize::new(0),
+            oversized_frames: AtomicUsize::new(0),
+            swaps: AtomicUsize::new(0),
+            flush_errors: AtomicUsize::new(0),
+            frame_sizes: [const { AtomicUsize::new(0) }; FRAME_SIZE_BUCKETS],
+        }
+    }
+
+    /// Record the bytes of a frame written to a buffer. Only called from within a
+    /// critical section.
+    #[inline]
+    pub(super) fn record_write(&self, len: usize) {
+        add(&self.bytes_written, len);
+    }
+
+    /// Record a dropped frame. Only called from within a critical section.
+    #[inline]
+    pub(super) fn record_drop(&self) {
+        add(&self.frames_dropped, 1);
+    }
+
+    /// Record a frame dropped for lack of a free buffer. Only called from within a
+    /// critical section.
+    #[inline]
+    pub(super) fn record_stall(&self) {
+        add(&self.stalls, 1);
+    }
+
+    /// Record a frame dropped for being larger than a buffer. Only called from within a
+    /// critical section.
+    #[inline]
+    pub(super) fn record_oversized(&self) {
+        add(&self.oversized_frames, 1);
+    }
+
+    /// Record a frame of `len` bytes written to a buffer. Only called from within a
+    /// critical section.
+    #[inline]
+    pub(super) fn record_frame(&self, len: usize) {
+        let bucket = FRAME_SIZE_BOUNDS
+            .iter()
+            .position(|&bound| len < bound)
+            .unwrap_or(FRAME_SIZE_BOUNDS.len());
+        add(&self.frame_sizes[bucket], 1);
+    }
+
+    /// Record a buffer swap. Only called from within a critical section.
+    #[inline]
+    pub(super) fn record_swap(&self) {
+        add(&self.swaps, 1);
+    }
+
+    /// Record the outcome of sending a buffer of `len` bytes.
+    pub(super) fn record_flush(&self, len: usize, ok: bool) {
+        critical_section::with(|_| {
+            if ok {
+                add(&self.bytes_sent, len);
+            } else {
+                add(&self.flush_errors, 1);
+            }
+        });
+    }
+
+    /// Read all the counters.
+    fn snapshot(&self) -> LoggerStats {
+        LoggerStats {
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            frames_dropped: self.frames_dropped.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+            oversized_frames: self.oversized_frames.load(Ordering::Relaxed),
+            swaps: self.swaps.load(Ordering::Relaxed),
+            flush_errors: self.flush_errors.load(Ordering::Relaxed),
+            frame_sizes: self
+                .frame_sizes
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Add `n` to a counter, wrapping on overflow.
+#[inline]
+fn add(counter: &AtomicUsize, n: usize) {
+    let value = counter.load(Ordering::Relaxed);
+    counter.store(value.wrapping_add(n), Ordering::Relaxed);
+}
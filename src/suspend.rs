@@ -0,0 +1,92 @@
+//! Tracking of USB bus suspend.
+
+use core::{cell::Cell, sync::atomic::Ordering};
+
+use critical_section::Mutex;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_usb::{driver::Driver, Builder, Handler};
+use portable_atomic::AtomicBool;
+use static_cell::ConstStaticCell;
+
+/// The USB bus is suspended.
+static SUSPENDED: AtomicBool = AtomicBool::new(false);
+
+/// Signalled when the USB bus is resumed, or the device is disconnected or reset.
+static RESUMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Called when the bus is suspended or resumed, set with `on_suspend_change`.
+static CALLBACK: Mutex<Cell<Option<SuspendCallback>>> = Mutex::new(Cell::new(None));
+
+/// Function called with `true` when the bus is suspended and `false` when resumed.
+type SuspendCallback = fn(bool);
+
+/// Handler registered on the USB device to track suspend.
+static HANDLER: ConstStaticCell<SuspendHandler> = ConstStaticCell::new(SuspendHandler);
+
+/// USB device handler recording whether the bus is suspended.
+struct SuspendHandler;
+
+impl SuspendHandler {
+    /// Record whether the bus is suspended, waking the logger task if not, and call the
+    /// application's callback if this changed.
+    fn set_suspended(&mut self, suspended: bool) {
+        let changed = SUSPENDED.swap(suspended, Ordering::Relaxed) != suspended;
+        if !suspended {
+            RESUMED.signal(());
+        }
+        if changed {
+            if let Some(callback) = critical_section::with(|cs| CALLBACK.borrow(cs).get()) {
+                callback(suspended);
+            }
+        }
+    }
+}
+
+impl Handler for SuspendHandler {
+    fn enabled(&mut self, _enabled: bool) {
+        self.set_suspended(false);
+    }
+
+    fn reset(&mut self) {
+        self.set_suspended(false);
+    }
+
+    fn suspended(&mut self, suspended: bool) {
+        self.set_suspended(suspended);
+    }
+}
+
+/// Registers a handler on the USB builder so that the logger task knows when the bus
+/// is suspended.
+///
+/// This is done by the `run` functions. It is only needed when building the USB device
+/// yourself, and may only be called once.
+///
+/// # Panics
+///
+/// Panics if called more than once.
+pub fn add_suspend_handler<'d, D: Driver<'d>>(builder: &mut Builder<'d, D>) {
+    builder.handler(HANDLER.take());
+}
+
+/// Sets a function called with `true` when the USB bus is suspended, and `false` when
+/// it is resumed (or the device is reset or disconnected while suspended).
+///
+/// This lets the application lower its power consumption while suspended, for
+/// instance by slowing its clocks, as the logger task does by waiting for the bus to
+/// resume. The function is called from the USB device's `run` future, so it should be
+/// quick and must not wait. It is only called when the suspend handler is registered,
+/// which the `run` functions do.
+pub fn on_suspend_change(callback: fn(bool)) {
+    critical_section::with(|cs| CALLBACK.borrow(cs).set(Some(callback)));
+}
+
+/// Returns `true` if the USB bus is suspended.
+pub(crate) fn is_suspended() -> bool {
+    SUSPENDED.load(Ordering::Relaxed)
+}
+
+/// Waits until the USB bus may have been resumed.
+pub(crate) async fn wait_resumed() {
+    RESUMED.wait().await;
+}
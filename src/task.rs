@@ -1,13 +1,30 @@
 //! Main task that runs the USB transport layer.
 
+use core::sync::atomic::Ordering;
+
+use embassy_futures::select::Either;
+use embassy_sync::{blocking_mutex::raw::RawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration};
 use embassy_usb::{
-    class::cdc_acm::{Sender, State},
+    class::cdc_acm::{CdcAcmClass, LineCoding, Receiver, Sender, State},
     driver::Driver,
-    Config,
+    Builder, Config, UsbDevice,
 };
-
+use portable_atomic::AtomicU64;
 use static_cell::{ConstStaticCell, StaticCell};
 
+#[cfg(not(feature = "disabled"))]
+use crate::controller::CONTROLLER;
+use crate::{
+    buffer::BUFFERSIZE,
+    clock::{Clock, EmbassyClock},
+    config::Sizes,
+    controller::FLUSH_REQUESTED,
+    suspend::add_suspend_handler,
+    transfer::Transfer,
+    transport::{LogTransport, TransportError},
+};
+
 /// Config descriptor buffer
 static CONFIG_DESCRIPTOR_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
 
@@ -20,22 +37,281 @@ static MSOS_DESCRIPTOR_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0
 /// Control buffer
 static CONTROL_BUF: ConstStaticCell<[u8; 256]> = ConstStaticCell::new([0u8; 256]);
 
+/// Flush interval set with `LoggerHandle::set_flush_interval`, in ticks, overriding
+/// `FlushConfig::flush_interval`. `NO_INTERVAL` if not set.
+pub(super) static FLUSH_INTERVAL: AtomicU64 = AtomicU64::new(NO_INTERVAL);
+
+/// Value of `FLUSH_INTERVAL` when no interval has been set.
+pub(super) const NO_INTERVAL: u64 = u64::MAX;
+
 /// CDC ACM state.
 static STATE: StaticCell<State> = StaticCell::new();
 
+/// CDC ACM state of the application data class created by `run_dual`.
+static DATA_STATE: StaticCell<State> = StaticCell::new();
+
+/// Configuration of the logger task's flush loop.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FlushConfig {
+    /// Time to wait between attempts to flush buffered frames to USB.
+    ///
+    /// Shorter intervals deliver log frames sooner and drain the buffers faster under
+    /// heavy logging, at the cost of waking the CPU more often. Longer intervals reduce
+    /// wakeups (useful on battery-powered devices) but increase latency and make it
+    /// more likely that bursts of logging overflow the buffers.
+    ///
+    /// The USB endpoint adds no polling interval of its own: frames are sent on a bulk
+    /// endpoint, which the host polls whenever bandwidth allows.
+    pub flush_interval: Duration,
+
+    /// Shortest time to wait between flushes while buffers are still waiting to be sent.
+    ///
+    /// After a flush that sent data, the logger waits this long if more buffers are
+    /// waiting, so that bursts of logging are drained quickly. `None` uses
+    /// [`flush_interval`](Self::flush_interval).
+    pub min_flush_interval: Option<Duration>,
+
+    /// Longest time to wait between flushes while there is nothing to send.
+    ///
+    /// Each flush that sends nothing doubles the wait, up to this bound, reducing
+    /// wakeups while idle. The wait returns to
+    /// [`flush_interval`](Self::flush_interval) once data is sent. `None` uses
+    /// [`flush_interval`](Self::flush_interval).
+    pub max_flush_interval: Option<Duration>,
+
+    /// Maximum time a partially-filled buffer may hold data before it is flushed.
+    ///
+    /// If `None`, buffers are only flushed once they are full (or `defmt::flush` is
+    /// called). Otherwise the active buffer is flushed once its oldest data is at least
+    /// this old. The age is checked once per [`flush_interval`](Self::flush_interval),
+    /// and measured from the first check that finds data in the buffer, so the latency
+    /// may exceed this by up to two flush intervals.
+    pub max_latency: Option<Duration>,
+
+    /// Log a warning when writes have been dropped because the buffers were full.
+    ///
+    /// The host decoder cannot tell that frames are missing, so the logger task emits
+    /// a `warn!` with the number of frames dropped since the last report. The warning
+    /// is itself logged through this crate, so it is subject to the `DEFMT_LOG` filter.
+    pub report_drops: bool,
+
+    /// Only send log frames while the host has asserted DTR on the logger's port.
+    ///
+    /// Most terminal programs assert DTR (Data Terminal Ready) when they open a serial
+    /// port, so this avoids sending frames when no program is reading them. When DTR is
+    /// deasserted the logger is disabled and buffered frames are discarded, so that
+    /// stale frames are not sent to the next reader. DTR is checked once per
+    /// [`flush_interval`](Self::flush_interval).
+    pub require_dtr: bool,
+
+    /// Maximum time to wait for the host to accept each USB packet.
+    ///
+    /// If the host stops reading while the endpoint is still enabled, writing a packet
+    /// can otherwise wait indefinitely. When a write times out and the retries set by
+    /// [`max_retries`](Self::max_retries) are exhausted, the rest of the buffer being
+    /// sent is discarded and the logger carries on, so newer frames can be sent once
+    /// the host resumes reading. `None` waits indefinitely.
+    pub write_timeout: Option<Duration>,
+
+    /// Send a zero-length packet after a transfer ending in a full-size packet.
+    ///
+    /// USB hosts treat a full-size packet as the middle of a transfer, so without a
+    /// terminating zero-length packet they may hold back the data until more arrives,
+    /// and logs appear only after the next message. This should only be turned off for
+    /// a host that misbehaves on zero-length packets.
+    pub send_zlp: bool,
+
+    /// Number of times to retry sending a packet that failed or timed out.
+    ///
+    /// A brief glitch then delays the log rather than losing the buffer being sent.
+    /// Only once the retries are exhausted is the rest of the buffer discarded. A
+    /// disconnected transport is never retried.
+    pub max_retries: u8,
+
+    /// Time to wait before the first retry of a packet, doubled for each further retry.
+    pub retry_backoff: Duration,
+
+    /// Keep buffered frames when the device is disconnected, and send them once it is
+    /// connected again.
+    ///
+    /// The buffers being sent when the disconnection is detected are always lost, as
+    /// it is unknown how much of them reached the host, but the active buffer, holding
+    /// the newest frames, is kept. By default the logger is disabled on disconnection
+    /// and every buffer is discarded, so that stale frames are not sent to the next
+    /// host. With this set, frames logged while disconnected are buffered as usual, and
+    /// dropped if the buffers fill, which suits brief disconnections such as a cable
+    /// glitch or the host re-enumerating the device.
+    pub keep_on_disconnect: bool,
+
+    /// Time to wait after the device is connected before sending frames.
+    ///
+    /// Some host software opens the port a moment after the device enumerates, and
+    /// misses the frames sent before. Frames logged during the wait are buffered as
+    /// usual, and sent once it is over, so a delay makes the first messages more
+    /// reliable at the cost of their latency. After a disconnection, frames logged
+    /// during the wait are only buffered with [`keep_on_disconnect`](Self::keep_on_disconnect)
+    /// set. The default is no delay.
+    pub startup_delay: Duration,
+
+    /// Wake the logger task as soon as a buffer is ready to be sent, rather than at the
+    /// next flush interval.
+    ///
+    /// A buffer is ready once it fills up (or reaches the watermark), so the task then
+    /// sleeps until there is something to send, which lowers both latency and wakeups.
+    /// The flush interval remains as a fallback, to send a partially-filled buffer when
+    /// no more frames are logged, along with [`max_latency`](Self::max_latency), so it
+    /// can be made longer. Waking the task adds a little work to the log call that fills
+    /// a buffer.
+    pub flush_when_ready: bool,
+
+    /// Send a [handshake](crate::handshake) giving the maximum packet size each time the
+    /// host attaches, before any buffered frame.
+    ///
+    /// Host tools can read it to configure themselves rather than assume the packet
+    /// size. Standard decoders such as `defmt-print` do not expect it, so it is off by
+    /// default.
+    pub send_handshake: bool,
+}
+
+impl FlushConfig {
+    /// The default configuration, flushing every 100ms.
+    pub const DEFAULT: Self = Self {
+        flush_interval: Duration::from_millis(100),
+        min_flush_interval: None,
+        max_flush_interval: None,
+        max_latency: None,
+        report_drops: true,
+        require_dtr: false,
+        write_timeout: None,
+        send_zlp: true,
+        max_retries: 3,
+        retry_backoff: Duration::from_millis(1),
+        keep_on_disconnect: false,
+        startup_delay: Duration::from_ticks(0),
+        flush_when_ready: false,
+        send_handshake: false,
+    };
+}
+
+impl Default for FlushConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Control signals set by the host on a CDC ACM port.
+#[derive(Clone, Copy, Debug)]
+pub struct ControlSignals {
+    /// The line coding (baud rate, data bits, parity and stop bits).
+    pub line_coding: LineCoding,
+    /// Data Terminal Ready, usually set when a program opens the port.
+    pub dtr: bool,
+    /// Request To Send.
+    pub rts: bool,
+}
+
 /// Builds the USB class and runs both the logger and USB.
 /// Requires the USB driver provided by the HAL and the maximum packet size
 /// allowed in the device.
-/// The user may provide an optional USB configuration to set the VID, PID and
-/// other information of the USB device. If none is provided a default
-/// configuration will be set.
+/// The maximum packet size is advertised in the descriptor of the logger's bulk
+/// endpoint, after being rounded to a valid bulk packet size. Packets are
+/// then split using the endpoint's maximum packet size, which is the advertised one,
+/// so the size used to send always matches the descriptor.
+/// The packet size must not be larger than the buffer size chosen with the
+/// `buffersize-*` features, or this panics at startup.
+/// The USB configuration sets the VID, PID and other information of the USB
+/// device. [`LoggerConfig`](crate::LoggerConfig) can be used to build one with the
+/// class codes the logger needs. With `composite_with_iads` set, as by `Config::new`,
+/// the device class, subclass and protocol must be `0xEF`, `0x02` and `0x01`, or this
+/// panics at startup with a message naming the field to change.
+///
+/// The logger flushes with the default [`FlushConfig`]; use [`run_with_flush_config`]
+/// to change it.
+///
+/// The USB device and the logger are run with `join`, and neither ever completes, so
+/// this never returns. To stop both together, use [`run_until`], which runs them with
+/// `select` instead.
 pub async fn run<D: Driver<'static>>(driver: D, size: usize, config: Config<'static>) {
-    use embassy_usb::{class::cdc_acm::CdcAcmClass, Builder};
+    run_with_flush_config(driver, size, config, FlushConfig::DEFAULT).await
+}
 
-    // Create the state of the CDC ACM device.
-    let state: &'static mut State<'static> = STATE.init(State::new());
+/// Builds the USB class and runs both the logger and USB, with the endpoint's packet
+/// size given as [`Sizes`].
+///
+/// This is the same as [`run`], with the endpoint's packet size taken from `sizes`.
+/// The log buffer size and count are fixed by the features.
+///
+/// # Panics
+///
+/// Panics if the endpoint's packets are larger than a log buffer, as [`run`] does.
+pub async fn run_with_sizes<D: Driver<'static>>(driver: D, sizes: Sizes, config: Config<'static>) {
+    run(driver, usize::from(sizes.endpoint_packet), config).await
+}
+
+/// Builds the USB class and runs both the logger and USB, using the given
+/// [`FlushConfig`] for the logger task.
+///
+/// See [`run`] for a description of the other arguments.
+pub async fn run_with_flush_config<D: Driver<'static>>(
+    driver: D,
+    size: usize,
+    config: Config<'static>,
+    flush_config: FlushConfig,
+) {
+    // Nothing to run when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        // Check the packet size before the USB builder takes the static buffers.
+        let packet_size = logger_packet_size(size);
+        let builder = default_builder(driver, config);
+
+        run_builder(builder, packet_size, flush_config).await;
+    }
+}
+
+/// Builds the USB class and runs both the logger and USB, using the given
+/// descriptor and control buffers instead of the crate's own.
+///
+/// The buffers are passed to [`embassy_usb::Builder::new`], and allow memory for
+/// USB descriptors to be managed by the application.
+///
+/// See [`run`] for a description of the other arguments.
+pub async fn run_with_buffers<D: Driver<'static>>(
+    driver: D,
+    size: usize,
+    config: Config<'static>,
+    config_descriptor_buf: &'static mut [u8],
+    bos_descriptor_buf: &'static mut [u8],
+    msos_descriptor_buf: &'static mut [u8],
+    control_buf: &'static mut [u8],
+) {
+    // Nothing to run when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        // Create the USB builder, once the packet size and the configuration are known
+        // to suit the logger.
+        let packet_size = logger_packet_size(size);
+        crate::config::validate_config(&config);
+        let mut builder = Builder::new(
+            driver,
+            config,
+            config_descriptor_buf,
+            bos_descriptor_buf,
+            msos_descriptor_buf,
+            control_buf,
+        );
+        add_suspend_handler(&mut builder);
+        #[cfg(feature = "history")]
+        crate::history::add_history_handler(&mut builder);
+
+        run_builder(builder, packet_size, FlushConfig::DEFAULT).await;
+    }
+}
 
-    // Create the USB builder.
+/// Creates a USB builder using the crate's descriptor and control buffers, with the
+/// suspend handler registered.
+fn default_builder<D: Driver<'static>>(driver: D, config: Config<'static>) -> Builder<'static, D> {
+    crate::config::validate_config(&config);
     let mut builder = Builder::new(
         driver,
         config,
@@ -44,74 +320,1668 @@ pub async fn run<D: Driver<'static>>(driver: D, size: usize, config: Config<'sta
         MSOS_DESCRIPTOR_BUF.take(),
         CONTROL_BUF.take(),
     );
+    add_suspend_handler(&mut builder);
+    #[cfg(feature = "history")]
+    crate::history::add_history_handler(&mut builder);
+    builder
+}
+
+/// Builds the USB class on the given builder and runs both the logger and USB.
+async fn run_builder<D: Driver<'static>>(
+    builder: Builder<'static, D>,
+    packet_size: u16,
+    flush_config: FlushConfig,
+) {
+    let (mut usb, sender) = build_logger_device(builder, packet_size);
+
+    // Run both futures concurrently.
+    embassy_futures::join::join(usb.run(), logger_with_flush_config(sender, flush_config)).await;
+}
 
-    // Create the class on top of the builder.
-    let class = CdcAcmClass::new(&mut builder, state, size as u16);
+/// Builds the USB class on the given builder, then the USB device, returning it along
+/// with the sender for the logger task.
+///
+/// The packet size must already be converted by [`logger_packet_size`], which the
+/// callers do before creating the builder, so that a wrong size panics before any of
+/// the static buffers are taken, and a rounding warning is only logged once.
+fn build_logger_device<D: Driver<'static>>(
+    mut builder: Builder<'static, D>,
+    packet_size: u16,
+) -> (UsbDevice<'static, D>, Sender<'static, D>) {
+    // Create the state of the CDC ACM device.
+    let state: &'static mut State<'static> = STATE.init(State::new());
+
+    // Create the class on top of the builder and get the sender.
+    let sender = logger_class(&mut builder, state, packet_size);
+    debug_assert_eq!(
+        LogTransport::max_packet_size(&sender),
+        usize::from(packet_size),
+        "The sender's packet size differs from the advertised one"
+    );
 
     // Build the USB.
-    let mut usb = builder.build();
+    (builder.build(), sender)
+}
+
+/// Builds the USB class and runs both the logger and USB until `shutdown` is
+/// signalled, then sends the buffered frames and returns.
+///
+/// This is meant for tests, for instance on hardware in the loop, that need to stop
+/// the logger once done and know that every frame was delivered. Returns `true` if all
+/// the buffered frames were sent, as with [`logger_until`], which describes the
+/// shutdown.
+///
+/// Unlike [`run`], which runs the USB device and the logger with `join`, this runs
+/// them with `select`: once the logger has finished, the USB device future is dropped,
+/// so both are torn down together and nothing keeps running in the background. The
+/// USB peripheral is left as it was, so the device should be reset or powered down
+/// afterwards, or the driver's bus disabled, before it is used again.
+///
+/// See [`run`] for a description of the other arguments.
+pub async fn run_until<D, M>(
+    driver: D,
+    size: usize,
+    config: Config<'static>,
+    shutdown: &Signal<M, ()>,
+    timeout: Duration,
+) -> bool
+where
+    D: Driver<'static>,
+    M: RawMutex,
+{
+    // Nothing to run when logging is disabled at compile time.
+    #[cfg(feature = "disabled")]
+    {
+        shutdown.wait().await;
+        true
+    }
+    #[cfg(not(feature = "disabled"))]
+    {
+        let packet_size = logger_packet_size(size);
+        let (mut usb, sender) = build_logger_device(default_builder(driver, config), packet_size);
+
+        // The USB device never stops on its own, so this completes with the logger.
+        match embassy_futures::select::select(
+            usb.run(),
+            logger_until(sender, FlushConfig::DEFAULT, shutdown, timeout),
+        )
+        .await
+        {
+            Either::First(never) => never,
+            Either::Second(flushed) => flushed,
+        }
+    }
+}
+
+/// Builds a USB device with two CDC ACM classes, one for logging and one for
+/// application data, and runs the logger, USB and the application concurrently.
+///
+/// The first class carries defmt frames, as with [`run`]. The sender and receiver of
+/// the second class are passed to `app`, so the host sees two serial ports: one for
+/// logs and one for data.
+///
+/// See [`run`] for a description of the other arguments.
+pub async fn run_dual<D, F>(driver: D, size: usize, config: Config<'static>, app: F)
+where
+    D: Driver<'static>,
+    F: AsyncFnOnce(Sender<'static, D>, Receiver<'static, D>),
+{
+    // Nothing to run when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        // Check the packet size before the USB builder takes the static buffers.
+        let packet_size = logger_packet_size(size);
+        let mut builder = default_builder(driver, config);
+
+        // Create the state of both CDC ACM devices.
+        let state: &'static mut State<'static> = STATE.init(State::new());
+        let data_state: &'static mut State<'static> = DATA_STATE.init(State::new());
+
+        // Create the logger class, then the data class, with the same packet size.
+        let sender = logger_class(&mut builder, state, packet_size);
+        let (data_sender, data_receiver) =
+            CdcAcmClass::new(&mut builder, data_state, packet_size).split();
+
+        // Build the USB.
+        let mut usb = builder.build();
+
+        // Run all three futures concurrently.
+        embassy_futures::join::join3(usb.run(), logger(sender), app(data_sender, data_receiver))
+            .await;
+    }
+}
+
+/// Builds the USB class and runs the logger and USB, passing the receiver of the
+/// logger's CDC ACM port to `app`, which runs concurrently.
+///
+/// This turns the log port into a bidirectional channel: `app` can read commands sent
+/// by the host, for instance to flush the buffers or to enable and disable logging
+/// with a [`LoggerHandle`](crate::LoggerHandle).
+///
+/// See [`run`] for a description of the other arguments.
+pub async fn run_with_receiver<D, F>(driver: D, size: usize, config: Config<'static>, app: F)
+where
+    D: Driver<'static>,
+    F: AsyncFnOnce(Receiver<'static, D>),
+{
+    // Nothing to run when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        // Check the packet size before the USB builder takes the static buffers.
+        let packet_size = logger_packet_size(size);
+        let mut builder = default_builder(driver, config);
+
+        // Create the state of the CDC ACM device.
+        let state: &'static mut State<'static> = STATE.init(State::new());
+
+        // Create the class on top of the builder, keeping the receiver.
+        let (sender, receiver) = CdcAcmClass::new(&mut builder, state, packet_size).split();
+
+        // Build the USB.
+        let mut usb = builder.build();
+
+        // Run all three futures concurrently.
+        embassy_futures::join::join3(usb.run(), logger(sender), app(receiver)).await;
+    }
+}
+
+/// Builds the USB class and runs both the logger and USB, calling `on_change` whenever
+/// the host changes the control signals of the logger's CDC ACM port.
+///
+/// This allows the application to react to the line coding (baud rate) or DTR/RTS set
+/// by the host, for instance to reset into a bootloader when the port is opened at
+/// 1200 baud.
+///
+/// See [`run`] for a description of the other arguments.
+pub async fn run_with_control_handler<D, F>(
+    driver: D,
+    size: usize,
+    config: Config<'static>,
+    mut on_change: F,
+) where
+    D: Driver<'static>,
+    F: FnMut(ControlSignals),
+{
+    // Nothing to run when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        // Check the packet size before the USB builder takes the static buffers.
+        let packet_size = logger_packet_size(size);
+        let mut builder = default_builder(driver, config);
+
+        // Create the state of the CDC ACM device.
+        let state: &'static mut State<'static> = STATE.init(State::new());
+
+        // Create the class on top of the builder, keeping the control change monitor.
+        let class = CdcAcmClass::new(&mut builder, state, packet_size);
+        let (sender, receiver, control) = class.split_with_control();
+
+        // Build the USB.
+        let mut usb = builder.build();
+
+        // Pass the control signals to the handler whenever they change.
+        let control_fut = async {
+            loop {
+                control.control_changed().await;
+                on_change(ControlSignals {
+                    line_coding: receiver.line_coding(),
+                    dtr: receiver.dtr(),
+                    rts: receiver.rts(),
+                });
+            }
+        };
+
+        // Run all three futures concurrently.
+        embassy_futures::join::join3(usb.run(), logger(sender), control_fut).await;
+    }
+}
+
+/// Sets the flush interval of the logger task, overriding the configured one, and
+/// wakes the task so that it takes effect straight away.
+pub(crate) fn set_flush_interval(interval: Duration) {
+    let ticks = interval.as_ticks().min(NO_INTERVAL - 1);
+    FLUSH_INTERVAL.store(ticks, Ordering::Relaxed);
+    FLUSH_REQUESTED.signal(());
+}
+
+/// Converts a maximum packet size to one allowed for a bulk endpoint.
+///
+/// Full-speed bulk endpoints may use 8, 16, 32 or 64 bytes, and high-speed bulk
+/// endpoints 512 bytes. Any other size is rounded down to the nearest allowed size (or
+/// up to 8), with a warning, as the host would otherwise fail to enumerate the device.
+pub(crate) fn packet_size(size: usize) -> u16 {
+    let valid = match size {
+        512.. => 512,
+        64.. => 64,
+        32.. => 32,
+        16.. => 16,
+        _ => 8,
+    };
+    if valid != size {
+        defmt::warn!(
+            "defmt: packet size {=usize} is not valid for a bulk endpoint, using {=usize}",
+            size,
+            valid
+        );
+    }
+    valid as u16
+}
+
+/// Converts a maximum packet size to one allowed for the logger's bulk endpoint.
+///
+/// As [`packet_size`], and also checks that a packet fits in a buffer.
+///
+/// # Panics
+///
+/// Panics if the packet size is larger than the buffer size, as every buffer would then
+/// be sent as a single short packet. A larger `buffersize-*` feature must be selected.
+pub(crate) fn logger_packet_size(size: usize) -> u16 {
+    let size = packet_size(size);
+    assert!(
+        usize::from(size) <= BUFFERSIZE,
+        "The packet size is larger than the buffer size; select a larger buffersize-* feature"
+    );
+    size
+}
+
+/// Registers a CDC ACM class for logging on an existing USB builder.
+///
+/// This allows the logger to be part of a composite device alongside other classes.
+/// The class uses an interrupt IN, a bulk OUT and a bulk IN endpoint, which cannot be
+/// reduced; [`add_bulk_logger_class`](crate::add_bulk_logger_class) uses a single
+/// endpoint instead.
+/// Building the device and running it, and running the [`logger`] task with the
+/// returned sender, are left to the caller.
+///
+/// `max_packet_size` is rounded to a valid bulk packet size and advertised in the
+/// endpoint descriptor. The logger task splits data using the returned sender's
+/// [`max_packet_size`](Sender::max_packet_size), which is that rounded size, so it may
+/// be smaller than the `max_packet_size` passed in.
+///
+/// # Panics
+///
+/// Panics if `max_packet_size` is larger than the buffer size chosen with the
+/// `buffersize-*` features.
+pub fn add_logger_class<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut State<'d>,
+    max_packet_size: u16,
+) -> Sender<'d, D> {
+    // Create the class on top of the builder, with a valid packet size.
+    logger_class(builder, state, logger_packet_size(max_packet_size.into()))
+}
+
+/// Registers the CDC ACM class for logging, as [`add_logger_class`], with a packet size
+/// already converted by [`logger_packet_size`], which is used as it is.
+fn logger_class<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    state: &'d mut State<'d>,
+    max_packet_size: u16,
+) -> Sender<'d, D> {
+    let class = CdcAcmClass::new(builder, state, max_packet_size);
 
     // Get the sender.
     let (sender, _) = class.split();
-
-    // Run both futures concurrently.
-    embassy_futures::join::join(usb.run(), logger(sender)).await;
+    sender
 }
 
 /// Runs the logger task.
-pub async fn logger<'d, D: Driver<'d>>(mut sender: Sender<'d, D>) {
-    use embassy_time::{Duration, Timer};
+///
+/// The logger sends frames over the given transport, usually the CDC ACM [`Sender`]
+/// created by [`add_logger_class`].
+///
+/// The logger flushes with the default [`FlushConfig`]; use
+/// [`logger_with_flush_config`] to change it.
+pub async fn logger<T: LogTransport>(transport: T) {
+    logger_with_flush_config(transport, FlushConfig::DEFAULT).await
+}
+
+/// Runs the logger task, using the given [`FlushConfig`].
+pub async fn logger_with_flush_config<T: LogTransport>(transport: T, flush_config: FlushConfig) {
+    logger_with_tee(transport, flush_config, async |_: &[u8]| {}).await
+}
+
+/// Runs the logger task, using the given [`FlushConfig`] and passing each chunk of
+/// data to `tee` before it is sent.
+///
+/// This allows the logs to be kept elsewhere as well, such as in a flash ring buffer
+/// for post-mortem analysis. `tee` sees the bytes exactly as they are sent, in the
+/// order they are sent, including compression and any headers. Chunks sent more than
+/// once because of a retry are only passed to `tee` once, but chunks discarded
+/// because sending them failed are still passed to it. Frames sent by a blocking
+/// flush (with the `panic-flush` feature) are not passed to `tee`.
+///
+/// Each chunk is sent once `tee` returns, so it should be quick. If
+/// [`write_timeout`](FlushConfig::write_timeout) is set, `tee` is given at most that
+/// long, after which the chunk is sent anyway.
+pub async fn logger_with_tee<T, F>(transport: T, flush_config: FlushConfig, tee: F)
+where
+    T: LogTransport,
+    F: AsyncFnMut(&[u8]),
+{
+    run_logger(transport, flush_config, tee, EmbassyClock).await
+}
 
-    use embassy_usb::driver::EndpointError;
+/// Runs the logger task, using the given [`FlushConfig`] and measuring its intervals
+/// and timeouts with `clock` instead of `embassy_time`.
+///
+/// This is meant for testing timing on the host, with a clock that advances virtually
+/// such as the `testing` feature's `VirtualClock`, so that flush intervals and timeouts
+/// elapse instantly and deterministically. Time spent by the logger outside its own
+/// waits, such as the age of buffers with [`max_latency`](FlushConfig::max_latency),
+/// is also measured with `clock`.
+pub async fn logger_with_clock<T, C>(transport: T, flush_config: FlushConfig, clock: C)
+where
+    T: LogTransport,
+    C: Clock,
+{
+    run_logger(transport, flush_config, async |_: &[u8]| {}, clock).await
+}
 
-    // Get a reference to the controller.
-    let controller = &super::controller::CONTROLLER;
-    // Only attempt to write what the sender will accept.
-    let packet_size = sender.max_packet_size() as usize;
+/// Runs the logger task with the given configuration, tee and clock.
+async fn run_logger<T, F, C>(mut transport: T, flush_config: FlushConfig, mut tee: F, clock: C)
+where
+    T: LogTransport,
+    F: AsyncFnMut(&[u8]),
+    C: Clock,
+{
+    // Nothing is ever buffered when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        let mut flush_loop = FlushLoop::new(flush_config, clock);
 
-    'main: loop {
+        loop {
+            flush_loop.connect(&mut transport).await;
+            flush_loop.serve(&mut transport, &mut tee).await;
+        }
+    }
+}
+
+/// Runs the logger task, using the given [`FlushConfig`], until `shutdown` is
+/// signalled, then sends the buffered frames and returns.
+///
+/// Once signalled, the logger stops sending, and sends the frames still buffered,
+/// including the active buffer, as [`flush_all`] does but with `flush_config`, so that
+/// its write timeout, retries and zero-length packets still apply. The final flush is given at most
+/// `timeout`, so the shutdown completes even if the host is not reading. Returns `true`
+/// if all the buffered frames were sent, and `false` if the transport was
+/// disconnected, sending failed or the timeout elapsed.
+///
+/// A transfer interrupted by the shutdown is sent again from its start, so the host
+/// may receive its first frames twice. Frames logged during the final flush may not be
+/// sent.
+pub async fn logger_until<T, M>(
+    mut transport: T,
+    flush_config: FlushConfig,
+    shutdown: &Signal<M, ()>,
+    timeout: Duration,
+) -> bool
+where
+    T: LogTransport,
+    M: RawMutex,
+{
+    // Nothing is ever buffered when logging is disabled at compile time.
+    #[cfg(feature = "disabled")]
+    {
+        shutdown.wait().await;
+        true
+    }
+    #[cfg(not(feature = "disabled"))]
+    {
+        let mut flush_loop = FlushLoop::new(flush_config, EmbassyClock);
+        let run = async {
+            loop {
+                flush_loop.connect(&mut transport).await;
+                flush_loop
+                    .serve(&mut transport, &mut async |_: &[u8]| {})
+                    .await;
+            }
+        };
+        embassy_futures::select::select(run, shutdown.wait()).await;
+
+        // Buffered frames are discarded when disconnected, so there is nothing to send.
+        if !CONTROLLER.is_enabled() {
+            return false;
+        }
+        matches!(
+            with_timeout(
+                timeout,
+                flush_all_with_config(&mut transport, &flush_config, &EmbassyClock)
+            )
+            .await,
+            Ok(Ok(_))
+        ) && !CONTROLLER.needs_flush()
+    }
+}
+
+/// Runs the logger task, sending frames over `fallback` until `primary` is connected.
+///
+/// This allows frames logged before USB is ready (such as during early boot) to be sent
+/// over another transport, like RTT. Frames still buffered when `primary` connects are
+/// sent over `primary`. If `primary` is disconnected, the logger goes back to using
+/// `fallback`.
+pub async fn logger_with_fallback<P: LogTransport, F: LogTransport>(
+    mut primary: P,
+    mut fallback: F,
+    flush_config: FlushConfig,
+) {
+    // Nothing is ever buffered when logging is disabled at compile time.
+    #[cfg(not(feature = "disabled"))]
+    {
+        let mut flush_loop = FlushLoop::new(flush_config, EmbassyClock);
+
+        loop {
+            // Use the fallback transport until the primary one is connected.
+            let fallback_fut = async {
+                loop {
+                    flush_loop.connect(&mut fallback).await;
+                    flush_loop
+                        .serve(&mut fallback, &mut async |_: &[u8]| {})
+                        .await;
+                }
+            };
+            embassy_futures::select::select(primary.wait_connection(), fallback_fut).await;
+
+            flush_loop.connect(&mut primary).await;
+            flush_loop
+                .serve(&mut primary, &mut async |_: &[u8]| {})
+                .await;
+        }
+    }
+}
+
+/// State of the logger task's flush loop.
+#[cfg(not(feature = "disabled"))]
+struct FlushLoop<C: Clock> {
+    /// Configuration of the loop.
+    config: FlushConfig,
+    /// Clock measuring the intervals and timeouts of the loop.
+    clock: C,
+    /// Number of dropped frames already reported.
+    reported_drops: usize,
+}
+
+#[cfg(not(feature = "disabled"))]
+impl<C: Clock> FlushLoop<C> {
+    fn new(config: FlushConfig, clock: C) -> Self {
+        CONTROLLER.set_wake_on_swap(config.flush_when_ready);
+        Self {
+            config,
+            clock,
+            reported_drops: crate::dropped_frames(),
+        }
+    }
+
+    /// Wait for the transport to be connected, then enable the controller.
+    async fn connect<T: LogTransport>(&self, transport: &mut T) {
         // Wait for the device to be connected.
-        sender.wait_connection().await;
+        transport.wait_connection().await;
+
+        // Wait for a program to open the port, if required.
+        if self.config.require_dtr {
+            while !transport.dtr() {
+                self.clock.after(self.flush_interval()).await;
+            }
+        }
+
+        // Give the host software time to open the port.
+        if self.config.startup_delay > Duration::from_ticks(0) {
+            self.clock.after(self.config.startup_delay).await;
+        }
 
-        // Set the controller as enabled.
-        controller.enable();
+        // Set the controller as enabled, ending the startup window.
+        CONTROLLER.attach();
 
-        // Continually attempt to write buffered defmt bytes out over USB.
+        if self.config.send_handshake {
+            // A failure to send is detected by the next flush, so it can be ignored here.
+            let _ = send_handshake(transport, &self.config, &self.clock).await;
+        }
+    }
+
+    /// Continually attempt to write buffered defmt bytes out over the transport, until
+    /// it is disconnected.
+    async fn serve<T: LogTransport>(
+        &mut self,
+        transport: &mut T,
+        tee: &mut impl AsyncFnMut(&[u8]),
+    ) {
+        let mut interval = self.flush_interval();
         loop {
-            let flush_res = controller
-                .flush::<_, EndpointError>(async |bytes| {
-                    let mut was_max_size = false;
-                    for chunk in bytes.chunks(packet_size) {
-                        was_max_size = chunk.len() == packet_size;
-                        sender.write_packet(chunk).await?;
-                    }
-                    // The Embassy CDC ACM docs note that a transfer must be terminated with a
-                    // shorter packet, so we track the size of the last chunk sent, and send a
-                    // zero-length packet if the chunk was the maximum packet size to ensure it is
-                    // processed by the host.
-                    if was_max_size {
-                        sender.write_packet(&[]).await?;
+            if transport.suspended() {
+                // Nothing can be sent until the host resumes the bus. Keep the buffered
+                // frames, queueing the active buffer so that it is sent first on resume,
+                // and park the task without polling until then.
+                CONTROLLER.flush_current();
+                while transport.suspended() {
+                    crate::suspend::wait_resumed().await;
+                }
+            }
+
+            if self.config.require_dtr && !transport.dtr() {
+                // The port has been closed, so discard buffered frames and wait for it to
+                // be opened again.
+                CONTROLLER.disable();
+                return;
+            }
+
+            // Flush the active buffer if it has held data for too long.
+            if let Some(max_latency) = self.config.max_latency {
+                CONTROLLER.flush_stale(max_latency, self.clock.now());
+            }
+
+            let flushed = match flush_with_config(transport, &self.config, tee, &self.clock).await {
+                Err(FlushError::Transport(TransportError::Disabled)) => {
+                    // USB endpoint is now disabled, so disable the controller (and so
+                    // not accept any defmt log messages) and wait until reconnected,
+                    // unless the buffered frames are to be kept until then.
+                    if !self.config.keep_on_disconnect {
+                        CONTROLLER.disable();
                     }
-                    Ok(())
-                })
+                    return;
+                }
+                // The host is not reading, or the packet could not be sent, so the buffer
+                // was discarded. Carry on so that newer frames are sent.
+                Err(FlushError::Timeout | FlushError::Transport(TransportError::Failed)) => false,
+                Ok(flushed) => flushed,
+            };
+            interval = self.next_interval(interval, flushed);
+
+            self.report_drops();
+
+            // If the buffers filled up again while sending, frames are about to be
+            // dropped, so drain them without waiting until there is room again.
+            if flushed && !CONTROLLER.has_space() {
+                continue;
+            }
+
+            // While waiting, lend the transport out so a blocking flush can use it.
+            #[cfg(feature = "panic-flush")]
+            let _registered = crate::blocking::register(transport);
+
+            // Wait the flush interval, or until a flush is requested.
+            embassy_futures::select::select(self.clock.after(interval), FLUSH_REQUESTED.wait())
                 .await;
+        }
+    }
 
-            match flush_res {
-                Err(EndpointError::Disabled) => {
-                    // USB endpoint is now disabled, so disable the controller (and so
-                    // not accept any defmt log messages) and wait until reconnected.
-                    controller.disable();
-                    continue 'main;
+    /// Returns the flush interval, as set at runtime or in the configuration.
+    fn flush_interval(&self) -> Duration {
+        match FLUSH_INTERVAL.load(Ordering::Relaxed) {
+            NO_INTERVAL => self.config.flush_interval,
+            ticks => Duration::from_ticks(ticks),
+        }
+    }
+
+    /// Returns the time to wait before the next flush, adapting to whether the last
+    /// flush sent anything.
+    fn next_interval(&self, interval: Duration, flushed: bool) -> Duration {
+        let config = &self.config;
+        let flush_interval = self.flush_interval();
+        let min = config.min_flush_interval.unwrap_or(flush_interval);
+        let max = config.max_flush_interval.unwrap_or(flush_interval);
+        if flushed && CONTROLLER.needs_flush() {
+            // More is waiting, so come back soon.
+            min
+        } else if flushed {
+            flush_interval.max(min).min(max)
+        } else {
+            // Nothing was sent, so back off, without overflowing for very long intervals.
+            interval.checked_mul(2).unwrap_or(max).max(min).min(max)
+        }
+    }
+
+    /// Report any frames dropped since the last report.
+    ///
+    /// This is done here rather than in the controller because logging from within the
+    /// defmt critical section would re-enter the logger.
+    fn report_drops(&mut self) {
+        if !self.config.report_drops {
+            return;
+        }
+        let dropped = crate::dropped_frames();
+        if dropped != self.reported_drops {
+            // The count restarts from zero when taken with `take_dropped_frames`.
+            let new = dropped.checked_sub(self.reported_drops).unwrap_or(dropped);
+            defmt::warn!("defmt: {=usize} frames dropped", new);
+            self.reported_drops = dropped;
+        }
+    }
+}
+
+/// Performs a single flush of buffered defmt bytes out over the given transport.
+///
+/// This is the primitive used by [`logger`], for applications that want to drive
+/// flushing from their own scheduling instead of a dedicated task. Each call sends
+/// a buffer waiting to be flushed, along with any following buffers that
+/// are also waiting.
+///
+/// Returns `true` if any bytes were written, and `false` if there was nothing to
+/// flush or the logger is disabled.
+///
+/// If an error is returned, the buffer being flushed is lost. On
+/// `TransportError::Disabled` the caller should wait for the device to be
+/// reconnected before flushing again.
+pub async fn flush_once<T: LogTransport>(transport: &mut T) -> Result<bool, TransportError> {
+    // Nothing is ever buffered when logging is disabled at compile time.
+    #[cfg(feature = "disabled")]
+    {
+        Ok(false)
+    }
+    #[cfg(not(feature = "disabled"))]
+    {
+        let mut tee = async |_: &[u8]| {};
+        match flush_with_config(transport, &FlushConfig::DEFAULT, &mut tee, &EmbassyClock).await {
+            Ok(flushed) => Ok(flushed),
+            Err(FlushError::Transport(e)) => Err(e),
+            Err(FlushError::Timeout) => unreachable!("No write timeout was given."),
+        }
+    }
+}
+
+/// Sends everything currently buffered over the given transport, including the
+/// active buffer.
+///
+/// Unlike [`wait_for_drain`](crate::wait_for_drain), this does not rely on the logger
+/// task: the active buffer is queued for sending, then the buffers are sent with
+/// [`flush_once`] until none are left. This is meant for shutdown paths, such as
+/// emitting everything before a reset, and must not run while the logger task is
+/// using the same transport.
+///
+/// At most one call to [`flush_once`] is made per buffer, so this terminates even if
+/// new frames keep being logged, which may then be left buffered.
+///
+/// Returns `true` if any bytes were written. On error, the buffer being flushed is lost
+/// and the rest are left buffered.
+pub async fn flush_all<T: LogTransport>(transport: &mut T) -> Result<bool, TransportError> {
+    // Nothing is ever buffered when logging is disabled at compile time.
+    #[cfg(feature = "disabled")]
+    {
+        Ok(false)
+    }
+    #[cfg(not(feature = "disabled"))]
+    {
+        match flush_all_with_config(transport, &FlushConfig::DEFAULT, &EmbassyClock).await {
+            Ok(flushed) => Ok(flushed),
+            Err(FlushError::Transport(e)) => Err(e),
+            Err(FlushError::Timeout) => unreachable!("No write timeout was given."),
+        }
+    }
+}
+
+/// Sends everything currently buffered, as [`flush_all`], with the given configuration.
+#[cfg(not(feature = "disabled"))]
+async fn flush_all_with_config<T: LogTransport>(
+    transport: &mut T,
+    config: &FlushConfig,
+    clock: &impl Clock,
+) -> Result<bool, FlushError> {
+    CONTROLLER.flush_current();
+    let mut tee = async |_: &[u8]| {};
+    let mut flushed = false;
+    for _ in 0..crate::controller::BUFFERCOUNT {
+        if !flush_with_config(transport, config, &mut tee, clock).await? {
+            break;
+        }
+        flushed = true;
+    }
+    Ok(flushed)
+}
+
+/// Error while flushing a buffer.
+#[cfg(not(feature = "disabled"))]
+enum FlushError {
+    /// Sending a packet failed.
+    Transport(TransportError),
+    /// Sending a packet took longer than the write timeout.
+    Timeout,
+}
+
+#[cfg(not(feature = "disabled"))]
+impl From<TransportError> for FlushError {
+    fn from(e: TransportError) -> Self {
+        Self::Transport(e)
+    }
+}
+
+/// Performs a single flush of buffered defmt bytes out over the transport, giving up
+/// on the buffer if sending any packet takes longer than the configured write timeout.
+///
+/// Each packet is passed to `tee` before it is sent.
+#[cfg(not(feature = "disabled"))]
+async fn flush_with_config<T: LogTransport>(
+    transport: &mut T,
+    config: &FlushConfig,
+    tee: &mut impl AsyncFnMut(&[u8]),
+    clock: &impl Clock,
+) -> Result<bool, FlushError> {
+    // Do nothing if not enabled.
+    if !CONTROLLER.is_enabled() {
+        return Ok(false);
+    }
+
+    // Only attempt to write what the transport will accept.
+    let packet_size = transport.max_packet_size();
+
+    CONTROLLER
+        .flush::<_, FlushError>(async |buffers| {
+            // Send the buffers as a single stream, so that full packets are sent where
+            // possible.
+            #[cfg(feature = "sequence-numbers")]
+            let mut headers = [[0; crate::sequence::HEADER_LEN]; crate::controller::BUFFERCOUNT];
+            #[cfg(feature = "sequence-numbers")]
+            let buffers = &crate::sequence::prefix(buffers, &mut headers);
+            // The zero-length packet terminating a transfer can be turned off for
+            // misbehaving hosts.
+            let mut transfer = Transfer::new(buffers, packet_size, config.send_zlp);
+            while let Some(chunk) = transfer.next_packet() {
+                if !chunk.is_empty() {
+                    match config.write_timeout {
+                        Some(timeout) => {
+                            let _ = crate::clock::with_timeout(clock, timeout, tee(chunk)).await;
+                        }
+                        None => tee(chunk).await,
+                    }
                 }
-                Err(EndpointError::BufferOverflow) => {
-                    unreachable!("Sent chunks are limited to Sender max packet size.")
+                send_packet(transport, chunk, config, clock).await?;
+            }
+            Ok(())
+        })
+        .await
+}
+
+/// Sends the handshake giving the transport's maximum packet size, as a transfer of
+/// its own.
+#[cfg(not(feature = "disabled"))]
+async fn send_handshake<T: LogTransport>(
+    transport: &mut T,
+    config: &FlushConfig,
+    clock: &impl Clock,
+) -> Result<(), FlushError> {
+    let packet_size = transport.max_packet_size();
+    let handshake = crate::handshake::handshake(packet_size);
+    let buffers: [&[u8]; 1] = [&handshake];
+    let mut transfer = Transfer::new(&buffers, packet_size, config.send_zlp);
+    while let Some(chunk) = transfer.next_packet() {
+        send_packet(transport, chunk, config, clock).await?;
+    }
+    Ok(())
+}
+
+/// Sends a single packet, with the configured timeout and retries.
+///
+/// A packet that fails or times out is retried after an exponentially increasing
+/// delay. A disconnected transport is not retried, as the logger must wait for it to
+/// be reconnected.
+#[cfg(not(feature = "disabled"))]
+async fn send_packet<T: LogTransport>(
+    transport: &mut T,
+    packet: &[u8],
+    config: &FlushConfig,
+    clock: &impl Clock,
+) -> Result<(), FlushError> {
+    let mut backoff = config.retry_backoff;
+    let mut retries = config.max_retries;
+    loop {
+        let res = match config.write_timeout {
+            Some(timeout) => {
+                match crate::clock::with_timeout(clock, timeout, transport.send(packet)).await {
+                    Some(res) => res.map_err(FlushError::from),
+                    None => Err(FlushError::Timeout),
                 }
-                Ok(()) => (),
-            };
+            }
+            None => transport.send(packet).await.map_err(FlushError::from),
+        };
+        match res {
+            Err(FlushError::Transport(TransportError::Failed) | FlushError::Timeout)
+                if retries > 0 =>
+            {
+                retries -= 1;
+                clock.after(backoff).await;
+                backoff *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
+// Sequence number headers, channel tags and compression change the bytes sent, which
+// the tests do not expect, so they are tested in their own modules with the bytes
+// decoded by `host::decode`. The `disabled` feature removes the buffers.
+#[cfg(all(
+    test,
+    not(any(
+        feature = "channels",
+        feature = "compression",
+        feature = "disabled",
+        feature = "sequence-numbers"
+    ))
+))]
+mod tests {
+    extern crate std;
+
+    use core::future::Future;
+    use std::{vec, vec::Vec};
+
+    use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+    use embassy_time::Instant;
+
+    use super::*;
+    use crate::testing::{host, MemoryTransport, VirtualClock};
+
+    /// Configuration of the flush loop in the tests, which never logs drops.
+    const CONFIG: FlushConfig = FlushConfig {
+        report_drops: false,
+        flush_interval: Duration::from_millis(10),
+        ..FlushConfig::DEFAULT
+    };
+
+    /// Returns a frame of `len` bytes, all `byte`.
+    fn frame(byte: u8, len: usize) -> Vec<u8> {
+        vec![byte; len]
+    }
+
+    /// Logs `frame` as a single frame.
+    fn log(frame: &[u8]) {
+        crate::write_raw_frame(frame);
+    }
+
+    /// Waits until `clock` reaches `at`, letting other futures run meanwhile.
+    async fn until(clock: &VirtualClock, at: Duration) {
+        while clock.now() < Instant::from_ticks(at.as_ticks()) {
+            embassy_futures::yield_now().await;
+        }
+    }
+
+    /// A transport sending to a [`MemoryTransport`] borrowed from the test, for the
+    /// functions that take their transport by value.
+    struct Borrowed<'a>(&'a mut MemoryTransport);
+
+    impl LogTransport for Borrowed<'_> {
+        async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+            self.0.send(packet).await
+        }
+
+        fn max_packet_size(&self) -> usize {
+            self.0.max_packet_size()
+        }
+
+        async fn wait_connection(&mut self) {}
+    }
+
+    /// A transport whose host never reads, so that sending never completes.
+    struct Stalled;
+
+    impl LogTransport for Stalled {
+        async fn send(&mut self, _packet: &[u8]) -> Result<(), TransportError> {
+            core::future::pending().await
+        }
+
+        fn max_packet_size(&self) -> usize {
+            16
+        }
+
+        async fn wait_connection(&mut self) {}
+    }
+
+    /// Runs the flush loop over `transport` until `clock` reaches `at`, returning the
+    /// non-empty packets sent.
+    fn serve_until(
+        transport: &mut impl LogTransport,
+        config: FlushConfig,
+        clock: &VirtualClock,
+        at: Duration,
+    ) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut flush_loop = FlushLoop::new(config, clock);
+        let mut tee = async |packet: &[u8]| packets.push(packet.to_vec());
+        embassy_futures::block_on(embassy_futures::select::select(
+            flush_loop.serve(transport, &mut tee),
+            until(clock, at),
+        ));
+        packets
+    }
+
+    /// Runs the flush loop over `transport` until `clock` reaches `at`, running `script`
+    /// alongside it, and returns the time at which each non-empty packet was sent.
+    fn send_times(
+        transport: &mut MemoryTransport,
+        config: FlushConfig,
+        clock: &VirtualClock,
+        at: Duration,
+        script: impl Future<Output = ()>,
+    ) -> Vec<Duration> {
+        let mut times = Vec::new();
+        let mut flush_loop = FlushLoop::new(config, clock);
+        let mut tee = async |_: &[u8]| times.push(clock.now() - Instant::from_ticks(0));
+        embassy_futures::block_on(embassy_futures::select::select(
+            flush_loop.serve(transport, &mut tee),
+            embassy_futures::join::join(script, until(clock, at)),
+        ));
+        times
+    }
+
+    /// A transport that never sends a packet, recording when each was attempted.
+    struct Refusing<'a> {
+        /// Clock giving the time of the attempts.
+        clock: &'a VirtualClock,
+        /// Sending never completes, as when the host is not reading, rather than failing.
+        stall: bool,
+        /// Times at which packets were attempted.
+        attempts: Vec<Duration>,
+    }
+
+    impl<'a> Refusing<'a> {
+        fn new(clock: &'a VirtualClock, stall: bool) -> Self {
+            Self {
+                clock,
+                stall,
+                attempts: Vec::new(),
+            }
+        }
+    }
+
+    impl LogTransport for Refusing<'_> {
+        async fn send(&mut self, _packet: &[u8]) -> Result<(), TransportError> {
+            self.attempts
+                .push(self.clock.now() - Instant::from_ticks(0));
+            if self.stall {
+                core::future::pending().await
+            }
+            Err(TransportError::Failed)
+        }
+
+        fn max_packet_size(&self) -> usize {
+            16
+        }
+
+        async fn wait_connection(&mut self) {}
+    }
+
+    #[test]
+    fn flush_once_sends_nothing_when_empty() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        assert_eq!(
+            embassy_futures::block_on(flush_once(&mut transport)),
+            Ok(false)
+        );
+        assert_eq!(transport.packets, 0);
+    }
+
+    #[test]
+    fn flush_once_splits_a_buffer_into_packets() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        log(&frame(1, 40));
+        crate::request_flush();
+        assert_eq!(
+            embassy_futures::block_on(flush_once(&mut transport)),
+            Ok(true)
+        );
+        assert_eq!(transport.take(), frame(1, 40));
+        // Full packets, then a short one ending the transfer.
+        assert_eq!(transport.packets, 3);
+    }
+
+    #[test]
+    fn flush_once_ends_a_full_packet_with_a_zlp() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        log(&frame(1, 32));
+        crate::request_flush();
+        assert_eq!(
+            embassy_futures::block_on(flush_once(&mut transport)),
+            Ok(true)
+        );
+        assert_eq!(transport.take(), frame(1, 32));
+        assert_eq!(transport.packets, 3);
+    }
+
+    #[test]
+    fn flush_once_fails_on_a_disconnected_transport() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        transport.connected = false;
+        log(&frame(1, 10));
+        crate::request_flush();
+        let errors = crate::flush_errors();
+        assert_eq!(
+            embassy_futures::block_on(flush_once(&mut transport)),
+            Err(TransportError::Disabled)
+        );
+        // The buffer being sent is lost.
+        assert_eq!(crate::flush_errors(), errors + 1);
+        assert!(!CONTROLLER.needs_flush());
+    }
+
+    #[test]
+    fn flush_all_sends_every_buffer_in_order() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let big = BUFFERSIZE - 10;
+        log(&frame(1, big));
+        log(&frame(2, big));
+        log(&frame(3, 5));
+        assert_eq!(
+            embassy_futures::block_on(flush_all(&mut transport)),
+            Ok(true)
+        );
+        assert_eq!(
+            transport.take(),
+            [frame(1, big), frame(2, big), frame(3, 5)].concat()
+        );
+        assert_eq!(crate::buffer_usage().0, 0);
+        assert_eq!(
+            embassy_futures::block_on(flush_all(&mut transport)),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn flush_all_sends_buffers_in_ring_order() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let big = BUFFERSIZE - 10;
+        // Move on by one buffer, so that the next buffers filled wrap around the ring.
+        log(&frame(1, big));
+        log(&frame(2, big));
+        assert_eq!(
+            embassy_futures::block_on(flush_once(&mut transport)),
+            Ok(true)
+        );
+        assert_eq!(transport.take(), frame(1, big));
+        log(&frame(3, big));
+        log(&frame(4, 5));
+        assert_eq!(
+            embassy_futures::block_on(flush_all(&mut transport)),
+            Ok(true)
+        );
+        assert_eq!(
+            transport.take(),
+            [frame(2, big), frame(3, big), frame(4, 5)].concat()
+        );
+    }
+
+    #[test]
+    fn serve_sends_frames_as_packets() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        log(&frame(1, 40));
+        log(&frame(2, 8));
+        crate::request_flush();
+        let packets = serve_until(&mut transport, CONFIG, &clock, Duration::from_millis(50));
+        assert_eq!(
+            packets,
+            [&frame(1, 40)[..], &frame(2, 8)]
+                .concat()
+                .chunks(16)
+                .collect::<Vec<_>>()
+        );
+        // The transfer ended in a full packet, so a zero-length packet followed.
+        assert_eq!(transport.packets, 4);
+        assert_eq!(transport.take(), [frame(1, 40), frame(2, 8)].concat());
+    }
+
+    #[test]
+    fn serve_sends_frames_logged_while_running() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        let mut flush_loop = FlushLoop::new(CONFIG, &clock);
+        let mut tee = async |_: &[u8]| {};
+        let script = async {
+            for byte in 1..=3 {
+                log(&frame(byte, 10));
+                crate::request_flush();
+                until(&clock, Duration::from_millis(20 * u64::from(byte))).await;
+            }
+        };
+        embassy_futures::block_on(embassy_futures::select::select(
+            flush_loop.serve(&mut transport, &mut tee),
+            script,
+        ));
+        assert_eq!(
+            transport.take(),
+            [frame(1, 10), frame(2, 10), frame(3, 10)].concat()
+        );
+        assert_eq!(transport.packets, 3);
+    }
+
+    #[test]
+    fn serve_returns_when_disconnected() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        transport.connected = false;
+        let clock = VirtualClock::new();
+        log(&frame(1, 10));
+        crate::request_flush();
+        let mut flush_loop = FlushLoop::new(CONFIG, &clock);
+        embassy_futures::block_on(flush_loop.serve(&mut transport, &mut async |_: &[u8]| {}));
+        assert!(!crate::is_connected());
+    }
+
+    #[test]
+    fn disconnect_discards_the_buffers() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        transport.connected = false;
+        let clock = VirtualClock::new();
+        let big = BUFFERSIZE - 10;
+        log(&frame(1, big));
+        log(&frame(2, big));
+        let mut flush_loop = FlushLoop::new(CONFIG, &clock);
+        embassy_futures::block_on(flush_loop.serve(&mut transport, &mut async |_: &[u8]| {}));
+        assert!(!crate::is_connected());
+        assert_eq!(crate::buffer_usage().0, 0);
+
+        // Nothing logged while disconnected, nor before, is sent once connected again.
+        log(&frame(3, 5));
+        transport.connected = true;
+        CONTROLLER.attach();
+        crate::request_flush();
+        let packets = serve_until(&mut transport, CONFIG, &clock, Duration::from_millis(50));
+        assert!(packets.is_empty());
+    }
+
+    #[test]
+    fn active_buffer_is_kept_on_disconnect() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        transport.connected = false;
+        let clock = VirtualClock::new();
+        let config = FlushConfig {
+            keep_on_disconnect: true,
+            ..CONFIG
+        };
+        let big = BUFFERSIZE - 10;
+        log(&frame(1, big));
+        log(&frame(2, big));
+        let errors = crate::flush_errors();
+        let mut flush_loop = FlushLoop::new(config, &clock);
+        embassy_futures::block_on(flush_loop.serve(&mut transport, &mut async |_: &[u8]| {}));
+        // The buffer being sent is lost, but the active one is kept.
+        assert_eq!(crate::flush_errors(), errors + 1);
+        assert!(crate::is_connected());
+
+        // Logging carries on while disconnected, and everything is sent once connected.
+        log(&frame(3, 5));
+        transport.connected = true;
+        crate::request_flush();
+        serve_until(&mut transport, config, &clock, Duration::from_millis(50));
+        assert_eq!(transport.take(), [frame(2, big), frame(3, 5)].concat());
+    }
+
+    #[test]
+    fn serve_drains_a_burst_without_waiting() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        let config = FlushConfig {
+            flush_interval: Duration::from_secs(1),
+            ..CONFIG
+        };
+        log(&frame(1, 10));
+        crate::request_flush();
+
+        // While the first buffer is sent, fill every other one, leaving no room for the
+        // next frame.
+        let burst: Vec<Vec<u8>> = (2..)
+            .take(crate::controller::BUFFERCOUNT - 1)
+            .map(|byte| frame(byte, BUFFERSIZE - 2))
+            .collect();
+        let mut times = Vec::new();
+        let mut tee = async |_: &[u8]| {
+            if times.is_empty() {
+                for frame in &burst {
+                    log(frame);
+                }
+                assert!(!CONTROLLER.has_space());
+            }
+            times.push(clock.now());
+        };
+        let mut flush_loop = FlushLoop::new(config, &clock);
+        embassy_futures::block_on(embassy_futures::select::select(
+            flush_loop.serve(&mut transport, &mut tee),
+            until(&clock, Duration::from_millis(1)),
+        ));
+
+        // The burst was sent straight after the first buffer, before any interval.
+        assert_eq!(
+            transport.take(),
+            [vec![frame(1, 10)], burst].concat().concat()
+        );
+        assert!(times.iter().all(|&time| time == Instant::from_ticks(0)));
+        assert_eq!(crate::dropped_frames(), 0);
+    }
+
+    #[test]
+    fn logger_until_sends_the_active_buffer_on_shutdown() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let shutdown = Signal::<CriticalSectionRawMutex, ()>::new();
+        log(&frame(1, 10));
+        shutdown.signal(());
+        let timeout = Duration::from_secs(1);
+        assert!(embassy_futures::block_on(logger_until(
+            Borrowed(&mut transport),
+            CONFIG,
+            &shutdown,
+            timeout
+        )));
+        assert_eq!(transport.take(), frame(1, 10));
+        assert_eq!(crate::buffer_usage().0, 0);
+    }
+
+    #[test]
+    fn logger_until_sends_waiting_buffers_in_order_on_shutdown() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let shutdown = Signal::<CriticalSectionRawMutex, ()>::new();
+        let big = BUFFERSIZE - 10;
+        let run = logger_until(
+            Borrowed(&mut transport),
+            CONFIG,
+            &shutdown,
+            Duration::from_secs(1),
+        );
+        let script = async {
+            log(&frame(1, big));
+            log(&frame(2, 5));
+            shutdown.signal(());
+        };
+        let (flushed, ()) = embassy_futures::block_on(embassy_futures::join::join(run, script));
+        assert!(flushed);
+        assert_eq!(transport.take(), [frame(1, big), frame(2, 5)].concat());
+    }
+
+    #[test]
+    fn logger_until_flushes_with_its_config_on_shutdown() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let shutdown = Signal::<CriticalSectionRawMutex, ()>::new();
+        let config = FlushConfig {
+            send_zlp: false,
+            ..CONFIG
+        };
+        log(&frame(1, 16));
+        shutdown.signal(());
+        assert!(embassy_futures::block_on(logger_until(
+            Borrowed(&mut transport),
+            config,
+            &shutdown,
+            Duration::from_secs(1)
+        )));
+        // A full packet ends the transfer without a zero-length packet.
+        assert_eq!(transport.packets, 1);
+        assert_eq!(transport.take(), frame(1, 16));
+    }
+
+    #[test]
+    fn logger_until_gives_up_after_the_timeout() {
+        let _lock = host::lock();
+        let shutdown = Signal::<CriticalSectionRawMutex, ()>::new();
+        log(&frame(1, 10));
+        shutdown.signal(());
+        let timeout = Duration::from_millis(20);
+        let start = Instant::now();
+        assert!(!embassy_futures::block_on(logger_until(
+            Stalled, CONFIG, &shutdown, timeout
+        )));
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn logger_until_fails_when_disconnected() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        transport.connected = false;
+        let shutdown = Signal::<CriticalSectionRawMutex, ()>::new();
+        log(&frame(1, 10));
+        shutdown.signal(());
+        assert!(!embassy_futures::block_on(logger_until(
+            Borrowed(&mut transport),
+            CONFIG,
+            &shutdown,
+            Duration::from_secs(1)
+        )));
+        assert!(transport.take().is_empty());
+    }
+
+    /// Returns `ms` milliseconds.
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Logs a frame that does not fit after another, leaving the first buffer waiting
+    /// to be sent.
+    fn fill_buffer() {
+        log(&frame(1, BUFFERSIZE - 10));
+        log(&frame(2, 20));
+    }
+
+    #[test]
+    fn waiting_buffer_is_sent_at_the_next_interval() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        let script = async {
+            until(&clock, ms(25)).await;
+            fill_buffer();
+        };
+        let times = send_times(&mut transport, CONFIG, &clock, ms(100), script);
+        assert_eq!(times.first(), Some(&ms(30)));
+    }
+
+    #[test]
+    fn interval_backs_off_while_idle() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        let config = FlushConfig {
+            max_flush_interval: Some(ms(80)),
+            ..CONFIG
+        };
+        let script = async {
+            until(&clock, ms(100)).await;
+            fill_buffer();
+        };
+        // Flushes finding nothing at 0, 20, 60 and 140ms, each interval doubling from
+        // 10ms up to 80ms.
+        let times = send_times(&mut transport, config, &clock, ms(200), script);
+        assert_eq!(times.first(), Some(&ms(140)));
+    }
+
+    #[test]
+    fn runtime_flush_interval_overrides_the_configured_one() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        set_flush_interval(ms(40));
+        // Only the new interval matters here, not the flush that setting it requests.
+        FLUSH_REQUESTED.reset();
+        let script = async {
+            until(&clock, ms(25)).await;
+            fill_buffer();
+        };
+        let times = send_times(&mut transport, CONFIG, &clock, ms(100), script);
+        assert_eq!(times.first(), Some(&ms(40)));
+    }
+
+    #[test]
+    fn longest_flush_interval_does_not_overflow() {
+        let _lock = host::lock();
+        let clock = VirtualClock::new();
+        set_flush_interval(Duration::MAX);
+        let flush_loop = FlushLoop::new(CONFIG, &clock);
+        let interval = flush_loop.flush_interval();
+        assert_eq!(interval.as_ticks(), NO_INTERVAL - 1);
+        assert_eq!(flush_loop.next_interval(interval, false), interval);
+    }
+
+    #[test]
+    fn active_buffer_is_sent_after_the_maximum_latency() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        let config = FlushConfig {
+            max_latency: Some(ms(30)),
+            ..CONFIG
+        };
+        log(&frame(1, 10));
+        let times = send_times(&mut transport, config, &clock, ms(100), async {});
+        assert_eq!(times, [ms(30)]);
+        assert_eq!(transport.take(), frame(1, 10));
+    }
+
+    #[test]
+    fn active_buffer_is_kept_without_a_maximum_latency() {
+        let _lock = host::lock();
+        let mut transport = MemoryTransport::new(16);
+        let clock = VirtualClock::new();
+        log(&frame(1, 10));
+        let times = send_times(&mut transport, CONFIG, &clock, ms(100), async {});
+        assert!(times.is_empty());
+    }
+
+    #[test]
+    fn failed_packet_is_retried_with_backoff() {
+        let _lock = host::lock();
+        let clock = VirtualClock::new();
+        let mut transport = Refusing::new(&clock, false);
+        let config = FlushConfig {
+            retry_backoff: ms(1),
+            max_retries: 3,
+            ..CONFIG
+        };
+        fill_buffer();
+        let errors = crate::flush_errors();
+        serve_until(&mut transport, config, &clock, ms(50));
+        // The wait doubles before each retry, then the buffer is discarded.
+        assert_eq!(transport.attempts, [ms(0), ms(1), ms(3), ms(7)]);
+        assert_eq!(crate::flush_errors(), errors + 1);
+        assert!(!CONTROLLER.needs_flush());
+    }
+
+    #[test]
+    fn stalled_packet_times_out() {
+        let _lock = host::lock();
+        let clock = VirtualClock::new();
+        let mut transport = Refusing::new(&clock, true);
+        let config = FlushConfig {
+            write_timeout: Some(ms(5)),
+            retry_backoff: ms(1),
+            max_retries: 2,
+            ..CONFIG
+        };
+        fill_buffer();
+        let errors = crate::flush_errors();
+        serve_until(&mut transport, config, &clock, ms(50));
+        // Each attempt is given the write timeout, followed by the backoff.
+        assert_eq!(transport.attempts, [ms(0), ms(6), ms(13)]);
+        assert_eq!(crate::flush_errors(), errors + 1);
+        assert!(!CONTROLLER.needs_flush());
+    }
+
+    // A 512-byte packet, the largest, only fits in the buffers with `buffersize-512` or
+    // larger.
+    #[cfg(not(any(feature = "buffersize-512", feature = "buffersize-1024")))]
+    mod packet_size {
+        use embassy_usb::driver::{
+            Bus, ControlPipe, Endpoint, EndpointAddress, EndpointAllocError, EndpointError,
+            EndpointIn, EndpointInfo, EndpointOut, EndpointType, Event, Unsupported,
+        };
+
+        use super::*;
+
+        /// A USB driver that must never be used, for the entry points that panic before
+        /// setting up USB.
+        struct UnusedDriver;
+
+        /// The endpoints, bus and control pipe of [`UnusedDriver`], which it never creates.
+        enum Never {}
+
+        impl Driver<'static> for UnusedDriver {
+            type EndpointOut = Never;
+            type EndpointIn = Never;
+            type ControlPipe = Never;
+            type Bus = Never;
+
+            fn alloc_endpoint_out(
+                &mut self,
+                _ep_type: EndpointType,
+                _ep_addr: Option<EndpointAddress>,
+                _max_packet_size: u16,
+                _interval_ms: u8,
+            ) -> Result<Never, EndpointAllocError> {
+                unreachable!("the driver was used")
+            }
+
+            fn alloc_endpoint_in(
+                &mut self,
+                _ep_type: EndpointType,
+                _ep_addr: Option<EndpointAddress>,
+                _max_packet_size: u16,
+                _interval_ms: u8,
+            ) -> Result<Never, EndpointAllocError> {
+                unreachable!("the driver was used")
+            }
+
+            fn start(self, _control_max_packet_size: u16) -> (Never, Never) {
+                unreachable!("the driver was used")
+            }
+        }
+
+        impl Endpoint for Never {
+            fn info(&self) -> &EndpointInfo {
+                match *self {}
+            }
+
+            async fn wait_enabled(&mut self) {
+                match *self {}
+            }
+        }
+
+        impl EndpointOut for Never {
+            async fn read(&mut self, _buf: &mut [u8]) -> Result<usize, EndpointError> {
+                match *self {}
+            }
+        }
+
+        impl EndpointIn for Never {
+            async fn write(&mut self, _buf: &[u8]) -> Result<(), EndpointError> {
+                match *self {}
+            }
+        }
+
+        impl ControlPipe for Never {
+            fn max_packet_size(&self) -> usize {
+                match *self {}
+            }
+
+            async fn setup(&mut self) -> [u8; 8] {
+                match *self {}
+            }
+
+            async fn data_out(
+                &mut self,
+                _buf: &mut [u8],
+                _first: bool,
+                _last: bool,
+            ) -> Result<usize, EndpointError> {
+                match *self {}
+            }
+
+            async fn data_in(
+                &mut self,
+                _data: &[u8],
+                _first: bool,
+                _last: bool,
+            ) -> Result<(), EndpointError> {
+                match *self {}
+            }
+
+            async fn accept(&mut self) {
+                match *self {}
+            }
+
+            async fn reject(&mut self) {
+                match *self {}
+            }
+
+            async fn accept_set_address(&mut self, _addr: u8) {
+                match *self {}
+            }
+        }
+
+        impl Bus for Never {
+            async fn enable(&mut self) {
+                match *self {}
+            }
+
+            async fn disable(&mut self) {
+                match *self {}
+            }
+
+            async fn poll(&mut self) -> Event {
+                match *self {}
+            }
+
+            fn endpoint_set_enabled(&mut self, _ep_addr: EndpointAddress, _enabled: bool) {
+                match *self {}
+            }
+
+            fn endpoint_set_stalled(&mut self, _ep_addr: EndpointAddress, _stalled: bool) {
+                match *self {}
+            }
+
+            fn endpoint_is_stalled(&mut self, _ep_addr: EndpointAddress) -> bool {
+                match *self {}
+            }
+
+            async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+                match *self {}
+            }
+        }
+
+        /// Runs `future`, returning the message it panics with.
+        fn panic_message(future: impl Future) -> std::string::String {
+            let payload = std::panic::catch_unwind(core::panic::AssertUnwindSafe(|| {
+                embassy_futures::block_on(future);
+            }))
+            .expect_err("the future did not panic");
+            match payload.downcast::<&str>() {
+                Ok(message) => (*message).into(),
+                Err(payload) => *payload.downcast().expect("the panic has no message"),
+            }
+        }
 
-            // Wait the timeout.
-            // TODO: Make this configurable.
-            Timer::after(Duration::from_millis(100)).await;
+        #[test]
+        fn packets_larger_than_a_buffer_panic_in_every_run_function() {
+            let _lock = host::lock();
+            let config = || Config::new(0x1209, 0x0001);
+            let buffer = || std::boxed::Box::leak(std::boxed::Box::new([0; 256])).as_mut_slice();
+            let shutdown = Signal::<CriticalSectionRawMutex, ()>::new();
+            let messages = [
+                panic_message(crate::run(UnusedDriver, 512, config())),
+                panic_message(run_with_sizes(
+                    UnusedDriver,
+                    Sizes {
+                        endpoint_packet: 512,
+                    },
+                    config(),
+                )),
+                panic_message(run_with_flush_config(
+                    UnusedDriver,
+                    512,
+                    config(),
+                    FlushConfig::DEFAULT,
+                )),
+                panic_message(run_with_buffers(
+                    UnusedDriver,
+                    512,
+                    config(),
+                    buffer(),
+                    buffer(),
+                    buffer(),
+                    buffer(),
+                )),
+                panic_message(run_until(UnusedDriver, 512, config(), &shutdown, ms(10))),
+                panic_message(run_dual(UnusedDriver, 512, config(), async |_, _| {})),
+                panic_message(run_with_receiver(UnusedDriver, 512, config(), async |_| {})),
+                panic_message(run_with_control_handler(
+                    UnusedDriver,
+                    512,
+                    config(),
+                    |_| {},
+                )),
+            ];
+            for message in messages {
+                assert!(
+                    message.starts_with("The packet size is larger than the buffer size"),
+                    "unexpected panic: {message}"
+                );
+            }
         }
     }
 }
@@ -0,0 +1,327 @@
+//! In-memory transport for testing on the host, enabled with the `testing` feature.
+//!
+//! This is also used by the crate's own unit tests, along with the support in `host`.
+
+extern crate std;
+
+use core::cell::Cell;
+use std::vec::Vec;
+
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    clock::Clock,
+    transport::{LogTransport, TransportError},
+};
+
+/// A transport that collects the bytes it is sent in memory.
+///
+/// This allows logging to be tested on the host without a USB device, by flushing
+/// the buffers into it with [`flush_once`](crate::flush_once) and inspecting the
+/// collected bytes, which are the same defmt stream that would be sent over USB.
+///
+/// ```ignore
+/// let mut transport = MemoryTransport::new(64);
+/// defmt::info!("Hello");
+/// defmt::flush();
+/// embassy_futures::block_on(defmtusb::flush_once(&mut transport)).unwrap();
+/// assert!(!transport.bytes.is_empty());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MemoryTransport {
+    /// Bytes of all the packets sent, in order.
+    pub bytes: Vec<u8>,
+    /// Number of packets sent, including zero-length packets.
+    pub packets: usize,
+    /// Maximum packet size reported to the logger.
+    pub max_packet_size: usize,
+    /// Whether the transport is connected. Sending to a disconnected transport fails
+    /// with [`TransportError::Disabled`].
+    pub connected: bool,
+}
+
+impl MemoryTransport {
+    /// Creates an empty, connected transport with the given maximum packet size.
+    pub fn new(max_packet_size: usize) -> Self {
+        Self {
+            bytes: Vec::new(),
+            packets: 0,
+            max_packet_size,
+            connected: true,
+        }
+    }
+
+    /// Removes and returns the collected bytes.
+    pub fn take(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.bytes)
+    }
+}
+
+impl LogTransport for MemoryTransport {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+        if !self.connected {
+            return Err(TransportError::Disabled);
+        }
+        self.bytes.extend_from_slice(packet);
+        self.packets += 1;
+        Ok(())
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// The transport is connected as soon as `connected` is set, so this returns
+    /// immediately.
+    async fn wait_connection(&mut self) {}
+}
+
+/// A clock whose time only advances when it is waited on, or advanced explicitly.
+///
+/// Waiting with [`Clock::after`] moves the time forward by the duration straight away,
+/// then yields once so that other futures can run, so the logger's intervals and
+/// timeouts elapse instantly and always in the same order. Run the logger with it using
+/// [`logger_with_clock`](crate::logger_with_clock).
+///
+/// ```ignore
+/// let clock = VirtualClock::new();
+/// let logger = defmtusb::logger_with_clock(MemoryTransport::new(64), config, &clock);
+/// ```
+#[derive(Debug, Default)]
+pub struct VirtualClock {
+    /// Current time, in ticks.
+    ticks: Cell<u64>,
+}
+
+impl VirtualClock {
+    /// Creates a clock starting at time zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Moves the time forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        self.ticks.set(self.ticks.get() + duration.as_ticks());
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.ticks.get())
+    }
+
+    async fn after(&self, duration: Duration) {
+        self.advance(duration);
+        embassy_futures::yield_now().await
+    }
+}
+
+/// Support for the unit tests, which run on the host.
+// The tests drive the encoder and the buffers, which the `disabled` feature removes.
+#[cfg(all(test, not(feature = "disabled")))]
+pub(crate) mod host {
+    extern crate std;
+
+    use core::{sync::atomic::Ordering, task::Waker};
+    use std::sync::{Mutex, MutexGuard, OnceLock};
+
+    use embassy_time_driver::Driver;
+
+    use crate::{
+        controller::{LongFramePolicy, Mode, OverflowPolicy, CONTROLLER},
+        USB_ENCODER,
+    };
+
+    /// Held by the tests using the global logger state, so that they run one at a time.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Takes the lock for the global logger state, and returns the logger to its
+    /// initial state: enabled, attached, with default settings and empty buffers.
+    pub(crate) fn lock() -> MutexGuard<'static, ()> {
+        // A failed test poisons the lock, but leaves nothing that `reset` does not undo.
+        let guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        reset();
+        guard
+    }
+
+    /// Returns the global logger to its initial state.
+    fn reset() {
+        // SAFETY: The lock is held, so no frame is being logged by another test.
+        unsafe { USB_ENCODER.reset() };
+        CONTROLLER.set_mode(Mode::Drop);
+        CONTROLLER.set_overflow_policy(OverflowPolicy::DropNewest);
+        CONTROLLER.set_long_frame_policy(LongFramePolicy::Drop);
+        CONTROLLER.set_wake_on_swap(false);
+        CONTROLLER.set_watermark(usize::MAX);
+        crate::level::set_flush_level(None);
+        crate::task::FLUSH_INTERVAL.store(crate::task::NO_INTERVAL, Ordering::Relaxed);
+        CONTROLLER.attach();
+        // A test may leave a send unfinished, for instance when a final flush times out.
+        CONTROLLER.finish_send(0, true);
+        CONTROLLER.clear();
+        crate::controller::FLUSH_REQUESTED.reset();
+        crate::take_dropped_frames();
+        crate::take_dropped_bytes();
+        crate::reset_peak_buffer_usage();
+    }
+
+    /// Returns the bytes logged, from the bytes sent to the host, as the host would:
+    /// decompressing them, and removing sequence number headers and channel tags.
+    #[cfg(any(
+        feature = "channels",
+        feature = "compression",
+        feature = "sequence-numbers"
+    ))]
+    pub(crate) fn decode(bytes: &[u8]) -> std::vec::Vec<u8> {
+        #[cfg(feature = "compression")]
+        let bytes = &decompress(bytes);
+        #[cfg(feature = "sequence-numbers")]
+        let bytes = &strip_headers(bytes).1;
+        #[cfg(feature = "channels")]
+        let bytes = &split_channels(bytes)
+            .into_iter()
+            .flat_map(|(_, frames)| frames)
+            .collect::<std::vec::Vec<_>>();
+        bytes.to_vec()
+    }
+
+    /// Returns the bytes compressed by [`crate::compress`].
+    #[cfg(feature = "compression")]
+    pub(crate) fn decompress(bytes: &[u8]) -> std::vec::Vec<u8> {
+        use std::vec::Vec;
+
+        use crate::compress::ESCAPE;
+
+        let mut decompressed = Vec::new();
+        let mut bytes = bytes.iter().copied();
+        while let Some(byte) = bytes.next() {
+            if byte != ESCAPE {
+                decompressed.push(byte);
+                continue;
+            }
+            match bytes.next().expect("escape at the end of the stream") {
+                0 => decompressed.push(ESCAPE),
+                n => {
+                    let byte = bytes.next().expect("run at the end of the stream");
+                    decompressed.extend(core::iter::repeat_n(byte, n.into()));
+                }
+            }
+        }
+        decompressed
+    }
+
+    /// Returns the sequence numbers of the headers added by [`crate::sequence`], and the
+    /// bytes without the headers.
+    #[cfg(feature = "sequence-numbers")]
+    pub(crate) fn strip_headers(mut bytes: &[u8]) -> (std::vec::Vec<u32>, std::vec::Vec<u8>) {
+        use std::vec::Vec;
+
+        use crate::sequence::{HEADER_LEN, MARKER};
+
+        let mut sequences = Vec::new();
+        let mut rest = Vec::new();
+        while let Some((&byte, tail)) = bytes.split_first() {
+            match bytes.get(..HEADER_LEN) {
+                Some([0x00, m @ .., 0x00])
+                    if m[..3] == MARKER && m[3..].iter().all(|&b| b & 0x80 != 0) =>
+                {
+                    let number = m[3..]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, &b)| u32::from(b & 0x7F) << (7 * i))
+                        .sum();
+                    sequences.push(number);
+                    bytes = &bytes[HEADER_LEN..];
+                }
+                _ => {
+                    rest.push(byte);
+                    bytes = tail;
+                }
+            }
+        }
+        (sequences, rest)
+    }
+
+    /// Returns the runs of bytes between the tags added by [`crate::channel`], with the
+    /// channel of each.
+    #[cfg(feature = "channels")]
+    pub(crate) fn split_channels(mut bytes: &[u8]) -> std::vec::Vec<(u8, std::vec::Vec<u8>)> {
+        use std::vec::Vec;
+
+        use crate::channel::{MARKER, TAG_LEN};
+
+        let mut runs: Vec<(u8, Vec<u8>)> = Vec::new();
+        while let Some((&byte, tail)) = bytes.split_first() {
+            match bytes.get(..TAG_LEN) {
+                Some(&[0x00, m0, m1, channel, 0x00])
+                    if [m0, m1] == MARKER && channel & 0x80 != 0 =>
+                {
+                    runs.push((channel & 0x7F, Vec::new()));
+                    bytes = &bytes[TAG_LEN..];
+                }
+                _ => {
+                    match runs.last_mut() {
+                        Some((_, run)) => run.push(byte),
+                        None => runs.push((0, std::vec![byte])),
+                    }
+                    bytes = tail;
+                }
+            }
+        }
+        runs
+    }
+
+    /// Time driver following the host's monotonic clock, for the code using
+    /// `embassy_time` directly.
+    struct StdDriver;
+
+    impl Driver for StdDriver {
+        fn now(&self) -> u64 {
+            static START: OnceLock<std::time::Instant> = OnceLock::new();
+            let elapsed = START.get_or_init(std::time::Instant::now).elapsed();
+            (elapsed.as_micros() * u128::from(embassy_time_driver::TICK_HZ) / 1_000_000) as u64
+        }
+
+        fn schedule_wake(&self, _at: u64, waker: &Waker) {
+            // Timers are polled again straight away, which `block_on` is fine with.
+            waker.wake_by_ref();
+        }
+    }
+
+    embassy_time_driver::time_driver_impl!(static DRIVER: StdDriver = StdDriver);
+
+    // Frames logged with the defmt macros need a timestamp, which the crate only
+    // provides with the `timestamp` feature.
+    #[cfg(not(feature = "timestamp"))]
+    defmt::timestamp!("");
+
+    // Panics raised with defmt, as embassy-usb does, need a handler.
+    #[defmt::panic_handler]
+    fn defmt_panic() -> ! {
+        panic!("panicked with defmt")
+    }
+
+    /// Lock held for each frame with the `custom-lock` feature. The tests using the
+    /// logger run one at a time, so it has nothing to exclude.
+    #[cfg(feature = "custom-lock")]
+    struct TestLock;
+
+    /// Number of times `TestLock` was acquired, so that the tests can check it is used.
+    #[cfg(feature = "custom-lock")]
+    pub(crate) static TEST_LOCK_ACQUIRED: portable_atomic::AtomicUsize =
+        portable_atomic::AtomicUsize::new(0);
+
+    // SAFETY: The tests using the logger hold `LOCK`, so nothing else logs.
+    #[cfg(feature = "custom-lock")]
+    unsafe impl crate::LoggerLock for TestLock {
+        unsafe fn acquire() -> usize {
+            TEST_LOCK_ACQUIRED.fetch_add(1, Ordering::Relaxed);
+            0
+        }
+
+        unsafe fn release(_token: usize) {}
+    }
+
+    #[cfg(feature = "custom-lock")]
+    crate::set_logger_lock!(TestLock);
+}
@@ -0,0 +1,91 @@
+//! Plain text interleaved with defmt frames.
+//!
+//! Text written with a [`TextWriter`], for instance by a library that formats with
+//! `core::fmt`, is sent over the same port as the defmt frames, in segments that the
+//! host can tell apart from them:
+//!
+//! ```ignore
+//! TextWriter::segment(|w| write!(w, "modem: {}", response));
+//! ```
+//!
+//! Each segment is written as a single frame:
+//!
+//! - `0x00`, ending any partial frame before it.
+//! - The two [`MARKER`] bytes, `TX`.
+//! - The text, as UTF-8, with any `0x00` bytes removed.
+//! - `0x00`, so that the segment is delimited like a defmt frame.
+//!
+//! The host should split the stream on `0x00` bytes, print the segments starting with
+//! the marker as text, and pass the rest to the defmt decoder. In Python:
+//!
+//! ```python
+//! for frame in stream.split(b"\x00"):
+//!     if frame.startswith(b"TX"):
+//!         print(frame[2:].decode("utf-8", "replace"))
+//!     elif frame:
+//!         decode_defmt(frame + b"\x00")
+//! ```
+//!
+//! Segments are buffered like defmt frames: a segment is kept whole or dropped whole,
+//! and one longer than a buffer is dropped or truncated following the
+//! [`LongFramePolicy`](crate::LongFramePolicy). Segments rely on the rzcobs frame
+//! delimiters, so they cannot be used with the `encoding-raw` feature.
+
+use core::fmt;
+
+#[cfg(not(feature = "disabled"))]
+use crate::controller::CONTROLLER;
+
+/// Marker bytes identifying a text segment.
+pub const MARKER: [u8; 2] = *b"TX";
+
+/// Writes plain text to the logger, as a segment the host can separate from defmt
+/// frames.
+pub struct TextWriter {
+    _private: (),
+}
+
+impl TextWriter {
+    /// Calls `f` with a writer, sending all the text it writes as a single segment.
+    ///
+    /// `f` runs inside a critical section, like a defmt log call, so it should only
+    /// format text, and must not log with defmt. If the segment is dropped because the
+    /// logger is in use, `f` is not called and `Ok(())` is returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within a defmt log call, or if `f` logs with defmt, unless
+    /// the `drop-reentrant-frames` feature is enabled, in which case the inner frame is
+    /// dropped.
+    pub fn segment(f: impl FnOnce(&mut TextWriter) -> fmt::Result) -> fmt::Result {
+        #[cfg(feature = "disabled")]
+        return Ok(());
+        #[cfg(not(feature = "disabled"))]
+        crate::USB_ENCODER
+            .write_frame(|| {
+                // SAFETY: `write_frame` calls this within a critical section, between
+                // the start and end of a frame.
+                unsafe {
+                    CONTROLLER.write(&[0x00]);
+                    CONTROLLER.write(&MARKER);
+                }
+                let result = f(&mut TextWriter { _private: () });
+                // SAFETY: As above.
+                unsafe { CONTROLLER.write(&[0x00]) };
+                result
+            })
+            .unwrap_or(Ok(()))
+    }
+}
+
+impl fmt::Write for TextWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        #[cfg(not(feature = "disabled"))]
+        for part in s.as_bytes().split(|&byte| byte == 0x00) {
+            // SAFETY: A `TextWriter` only exists within `segment`, where the frame is
+            // being written.
+            unsafe { CONTROLLER.write(part) };
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Splitting of the buffers being sent into the packets of a USB transfer.
+//!
+//! This holds the decisions of a flush that do not depend on how packets are sent, so
+//! that the logger task and [`on_usb_interrupt`](crate::on_usb_interrupt) send exactly
+//! the same packets.
+
+/// Packets of the buffers being sent, as a single stream.
+#[cfg(not(feature = "compression"))]
+type Packets<'a> = crate::packets::Packets<'a>;
+/// Packets of the buffers being sent, compressed, as a single stream.
+#[cfg(feature = "compression")]
+type Packets<'a> = crate::compress::Packets<'a>;
+
+/// The packets sending the contents of a set of buffers in a single transfer.
+///
+/// Packets are produced the same way each time for the same buffers, so a caller that
+/// cannot send them all at once can produce them again and skip those already sent.
+pub(crate) struct Transfer<'a> {
+    /// Packets of the buffers not yet produced.
+    packets: Packets<'a>,
+    /// Maximum size of each packet.
+    packet_size: usize,
+    /// Terminate the transfer with a zero-length packet if it ends in a full one.
+    send_zlp: bool,
+    /// The last packet produced was of the maximum size.
+    was_max_size: bool,
+}
+
+impl<'a> Transfer<'a> {
+    /// Split the concatenation of `buffers` into packets of at most `packet_size` bytes.
+    ///
+    /// With `send_zlp`, a transfer ending in a full-size packet is terminated by a
+    /// zero-length packet, as the Embassy CDC ACM docs note that a transfer must end with
+    /// a shorter packet for the host to process it.
+    pub(crate) fn new(buffers: &'a [&'a [u8]], packet_size: usize, send_zlp: bool) -> Self {
+        Self {
+            packets: Packets::new(buffers, packet_size),
+            packet_size,
+            send_zlp,
+            was_max_size: false,
+        }
+    }
+
+    /// Returns the next packet to send, which is empty for the terminating zero-length
+    /// packet, or `None` once the transfer is complete.
+    pub(crate) fn next_packet(&mut self) -> Option<&[u8]> {
+        match self.packets.next_packet() {
+            Some(packet) => {
+                self.was_max_size = packet.len() == self.packet_size;
+                Some(packet)
+            }
+            None if self.was_max_size && self.send_zlp => {
+                self.was_max_size = false;
+                Some(&[])
+            }
+            None => None,
+        }
+    }
+}
+
+// Compression changes the bytes of the packets, which the tests do not expect.
+#[cfg(all(test, not(feature = "compression")))]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::Transfer;
+
+    /// Returns all the packets of the transfer, including any zero-length packet.
+    fn packets(buffers: &[&[u8]], packet_size: usize, send_zlp: bool) -> Vec<Vec<u8>> {
+        let mut transfer = Transfer::new(buffers, packet_size, send_zlp);
+        let mut packets = Vec::new();
+        while let Some(packet) = transfer.next_packet() {
+            packets.push(packet.to_vec());
+        }
+        packets
+    }
+
+    #[test]
+    fn buffer_is_split_into_packets() {
+        assert_eq!(
+            packets(&[&[1; 40]], 16, true),
+            [vec![1; 16], vec![1; 16], vec![1; 8]]
+        );
+    }
+
+    #[test]
+    fn buffers_are_sent_as_a_single_stream() {
+        // The end of the first buffer shares a full packet with the start of the second.
+        assert_eq!(
+            packets(&[&[1; 10], &[], &[2; 20]], 16, true),
+            [[vec![1; 10], vec![2; 6]].concat(), vec![2; 14]]
+        );
+    }
+
+    #[test]
+    fn full_last_packet_is_followed_by_a_zlp() {
+        assert_eq!(
+            packets(&[&[1; 20], &[2; 12]], 16, true),
+            [vec![1; 16], [vec![1; 4], vec![2; 12]].concat(), vec![]]
+        );
+    }
+
+    #[test]
+    fn zlp_is_not_sent_when_turned_off() {
+        assert_eq!(packets(&[&[1; 32]], 16, false), [vec![1; 16], vec![1; 16]]);
+    }
+
+    #[test]
+    fn empty_buffers_send_nothing() {
+        assert!(packets(&[&[], &[]], 16, true).is_empty());
+    }
+
+    #[test]
+    fn transfer_is_produced_the_same_again() {
+        let buffers: [&[u8]; 2] = [&[1; 30], &[2; 30]];
+        assert_eq!(packets(&buffers, 16, true), packets(&buffers, 16, true));
+    }
+}
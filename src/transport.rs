@@ -0,0 +1,222 @@
+//! Transports used by the logger task to send buffered frames to the host.
+
+use embassy_usb::{
+    class::cdc_acm::Sender,
+    driver::{Driver, Endpoint, EndpointError, EndpointIn},
+    Builder, FunctionBuilder,
+};
+
+/// Error returned by a [`LogTransport`] when sending a packet.
+///
+/// For the CDC ACM and bulk transports, `EndpointError::Disabled` (the device was reset,
+/// disconnected or deconfigured) maps to [`Disabled`](Self::Disabled). A suspended bus
+/// is not an error: sending waits until the host resumes the device, or until the
+/// [`write_timeout`](crate::FlushConfig::write_timeout), which is retried like
+/// [`Failed`](Self::Failed).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TransportError {
+    /// The transport is disconnected.
+    ///
+    /// The logger is disabled until the transport is connected again.
+    Disabled,
+    /// The packet could not be sent.
+    ///
+    /// The packet is retried as set by [`max_retries`](crate::FlushConfig::max_retries),
+    /// after which the rest of the buffer being sent is discarded.
+    Failed,
+}
+
+/// A transport that sends buffered defmt frames to the host, one packet at a time.
+#[allow(async_fn_in_trait)]
+pub trait LogTransport {
+    /// Send a single packet of at most [`max_packet_size`](Self::max_packet_size) bytes.
+    ///
+    /// An empty packet may be sent to terminate a transfer.
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError>;
+
+    /// The maximum number of bytes that can be sent in a single packet.
+    fn max_packet_size(&self) -> usize;
+
+    /// Wait until the transport is connected to the host.
+    async fn wait_connection(&mut self);
+
+    /// Returns `true` if the host has asserted DTR (Data Terminal Ready).
+    ///
+    /// Transports without a DTR signal always return `true`.
+    fn dtr(&self) -> bool {
+        true
+    }
+
+    /// Returns `true` if the transport is suspended, so that nothing can be sent until
+    /// it resumes.
+    ///
+    /// Transports that cannot be suspended always return `false`.
+    fn suspended(&self) -> bool {
+        false
+    }
+}
+
+impl<'d, D: Driver<'d>> LogTransport for Sender<'d, D> {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+        match self.write_packet(packet).await {
+            Ok(()) => Ok(()),
+            Err(EndpointError::Disabled) => Err(TransportError::Disabled),
+            Err(EndpointError::BufferOverflow) => {
+                unreachable!("Sent chunks are limited to Sender max packet size.")
+            }
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        Sender::max_packet_size(self) as usize
+    }
+
+    async fn wait_connection(&mut self) {
+        Sender::wait_connection(self).await
+    }
+
+    fn dtr(&self) -> bool {
+        Sender::dtr(self)
+    }
+
+    /// Requires the handler registered by [`add_suspend_handler`](crate::add_suspend_handler).
+    fn suspended(&self) -> bool {
+        crate::suspend::is_suspended()
+    }
+}
+
+/// A transport using a vendor-specific interface with a single bulk IN endpoint.
+///
+/// This avoids the overhead of CDC ACM and does not enumerate as a serial port, so it
+/// needs a host-side reader that reads from the endpoint directly (using libusb, for
+/// example). The endpoint carries the defmt stream exactly as the CDC ACM port does.
+pub struct BulkTransport<'d, D: Driver<'d>> {
+    /// The bulk IN endpoint.
+    endpoint: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> LogTransport for BulkTransport<'d, D> {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+        match self.endpoint.write(packet).await {
+            Ok(()) => Ok(()),
+            Err(EndpointError::Disabled) => Err(TransportError::Disabled),
+            Err(EndpointError::BufferOverflow) => {
+                unreachable!("Sent chunks are limited to endpoint max packet size.")
+            }
+        }
+    }
+
+    fn max_packet_size(&self) -> usize {
+        self.endpoint.info().max_packet_size as usize
+    }
+
+    async fn wait_connection(&mut self) {
+        self.endpoint.wait_enabled().await
+    }
+
+    /// Requires the handler registered by [`add_suspend_handler`](crate::add_suspend_handler).
+    fn suspended(&self) -> bool {
+        crate::suspend::is_suspended()
+    }
+}
+
+/// USB class code for vendor-specific interfaces.
+const USB_CLASS_VENDOR: u8 = 0xFF;
+
+/// Registers a vendor-specific interface with a bulk IN endpoint for logging on an
+/// existing USB builder.
+///
+/// Run the [`logger`](crate::logger) task with the returned transport.
+///
+/// # Panics
+///
+/// Panics if `max_packet_size` is larger than the buffer size chosen with the
+/// `buffersize-*` features.
+pub fn add_bulk_logger_class<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    max_packet_size: u16,
+) -> BulkTransport<'d, D> {
+    add_bulk_logger_class_with(builder, max_packet_size, |_| ())
+}
+
+/// Registers a vendor-specific interface with a bulk IN endpoint for logging, as
+/// [`add_bulk_logger_class`], calling `configure` with the interface's function to add
+/// descriptors to it.
+///
+/// This is meant for MS OS 2.0 function-level descriptors, which let Windows bind the
+/// WinUSB driver to the interface without an INF file. The descriptor set itself must
+/// be added to the builder with [`Builder::msos_descriptor`] before this is called:
+///
+/// ```ignore
+/// use embassy_usb::msos::{self, windows_version};
+///
+/// // Generate a GUID of your own for the device.
+/// const DEVICE_INTERFACE_GUIDS: &[&str] = &["{AFB9A6FB-30BA-44BC-9232-806CFC875321}"];
+///
+/// builder.msos_descriptor(windows_version::WIN8_1, 0);
+/// let transport = defmtusb::add_bulk_logger_class_with(&mut builder, 64, |function| {
+///     function.msos_feature(msos::CompatibleIdFeatureDescriptor::new("WINUSB", ""));
+///     function.msos_feature(msos::RegistryPropertyFeatureDescriptor::new(
+///         "DeviceInterfaceGUIDs",
+///         msos::PropertyData::RegMultiSz(DEVICE_INTERFACE_GUIDS),
+///     ));
+/// });
+/// ```
+///
+/// # Panics
+///
+/// Panics if `max_packet_size` is larger than the buffer size chosen with the
+/// `buffersize-*` features.
+pub fn add_bulk_logger_class_with<'d, D: Driver<'d>>(
+    builder: &mut Builder<'d, D>,
+    max_packet_size: u16,
+    configure: impl FnOnce(&mut FunctionBuilder<'_, 'd, D>),
+) -> BulkTransport<'d, D> {
+    let mut function = builder.function(USB_CLASS_VENDOR, 0, 0);
+    configure(&mut function);
+    let mut interface = function.interface();
+    let mut alt = interface.alt_setting(USB_CLASS_VENDOR, 0, 0, None);
+    let endpoint = alt.endpoint_bulk_in(
+        None,
+        crate::task::logger_packet_size(max_packet_size.into()),
+    );
+    BulkTransport { endpoint }
+}
+
+/// Size of the packets sent over RTT.
+///
+/// RTT has no packet size limit, but the size of the channel's buffer is not known,
+/// so packets are kept to the size of a full-speed USB packet, which a channel buffer
+/// holds several of.
+#[cfg(feature = "rtt")]
+const RTT_PACKET_SIZE: usize = 64;
+
+#[cfg(feature = "rtt")]
+impl LogTransport for rtt_target::UpChannel {
+    /// Writes the packet to the channel.
+    ///
+    /// If the channel is full, nothing is written and the packet is retried. Once part
+    /// of the packet has been written, the rest is written as the host reads the
+    /// channel, as a retry would send the written part again.
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+        let mut rest = packet;
+        while !rest.is_empty() {
+            let written = self.write(rest);
+            if written == 0 {
+                if rest.len() == packet.len() {
+                    return Err(TransportError::Failed);
+                }
+                embassy_futures::yield_now().await;
+            }
+            rest = &rest[written..];
+        }
+        Ok(())
+    }
+
+    fn max_packet_size(&self) -> usize {
+        RTT_PACKET_SIZE
+    }
+
+    /// RTT is always available.
+    async fn wait_connection(&mut self) {}
+}
@@ -0,0 +1,184 @@
+//! Transport sending frames over a UART or any other byte stream, enabled with the
+//! `uart` feature.
+
+use embedded_io_async::Write;
+
+use crate::{
+    task::{logger_with_flush_config, FlushConfig},
+    transport::{LogTransport, TransportError},
+};
+
+/// A transport writing frames to an [`embedded_io_async::Write`] sink, such as the
+/// transmit half of a UART.
+///
+/// The buffers, encoder and flush loop are the same as over USB; only the sink differs.
+/// The stream carries the same defmt frames, which the host decodes as it would the
+/// USB stream, for instance with `defmt-print` reading the serial port.
+///
+/// A byte stream has no packets, so the frames are written in chunks of at most the
+/// chunk size, which the logger takes as the packet size, for instance in the
+/// [handshake](crate::handshake). The zero-length packets that terminate USB transfers
+/// are skipped. The sink is always considered connected, and DTR is never asserted, so
+/// [`require_dtr`](crate::FlushConfig::require_dtr) must not be set.
+pub struct UartTransport<W> {
+    /// The sink the frames are written to.
+    writer: W,
+    /// Maximum number of bytes written at a time.
+    chunk_size: usize,
+}
+
+impl<W: Write> UartTransport<W> {
+    /// Chunk size of a transport created with [`new`](Self::new), the packet size of a
+    /// full-speed USB endpoint.
+    pub const DEFAULT_CHUNK_SIZE: usize = 64;
+
+    /// Creates a transport writing to `writer`,
+    /// [`DEFAULT_CHUNK_SIZE`](Self::DEFAULT_CHUNK_SIZE) bytes at a time.
+    pub fn new(writer: W) -> Self {
+        Self::with_chunk_size(writer, Self::DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Creates a transport writing to `writer`, at most `chunk_size` bytes at a time.
+    ///
+    /// Larger chunks make fewer writes, while smaller ones let the logger check its
+    /// write timeout more often on a slow UART.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `chunk_size` is zero.
+    pub fn with_chunk_size(writer: W, chunk_size: usize) -> Self {
+        assert!(chunk_size > 0, "The UART chunk size must not be zero");
+        Self { writer, chunk_size }
+    }
+
+    /// Returns the sink, consuming the transport.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> LogTransport for UartTransport<W> {
+    async fn send(&mut self, packet: &[u8]) -> Result<(), TransportError> {
+        // A zero-length packet has no meaning on a byte stream.
+        if packet.is_empty() {
+            return Ok(());
+        }
+        self.writer
+            .write_all(packet)
+            .await
+            .map_err(|_| TransportError::Failed)
+    }
+
+    /// A byte stream has no packets, so this is the chunk size.
+    fn max_packet_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// A byte stream is always available.
+    async fn wait_connection(&mut self) {}
+}
+
+/// Runs the logger task, writing frames to `writer`, such as the transmit half of a
+/// UART, instead of USB.
+///
+/// This is for boards without USB. It runs the same logger task as
+/// [`run`](crate::run), over a [`UartTransport`] with the default chunk size, using the
+/// given [`FlushConfig`].
+pub async fn run_uart<W: Write>(writer: W, flush_config: FlushConfig) {
+    logger_with_flush_config(UartTransport::new(writer), flush_config).await
+}
+
+// Sequence number headers, channel tags and compression change the bytes written, which
+// the tests do not expect, so they are tested in their own modules with the bytes
+// decoded by `host::decode`. The `disabled` feature removes the buffers.
+#[cfg(all(
+    test,
+    not(any(
+        feature = "channels",
+        feature = "compression",
+        feature = "disabled",
+        feature = "sequence-numbers"
+    ))
+))]
+mod tests {
+    extern crate std;
+
+    use core::{cell::RefCell, convert::Infallible, future::Future};
+    use std::{vec, vec::Vec};
+
+    use embassy_time::{Duration, Instant};
+    use embedded_io_async::ErrorType;
+
+    use super::*;
+    use crate::{handshake::handshake, testing::host};
+
+    /// Configuration of the logger in the tests, which never logs drops.
+    const CONFIG: FlushConfig = FlushConfig {
+        report_drops: false,
+        flush_interval: Duration::from_millis(10),
+        ..FlushConfig::DEFAULT
+    };
+
+    /// A sink recording each write in memory.
+    struct Sink<'a>(&'a RefCell<Vec<Vec<u8>>>);
+
+    impl ErrorType for Sink<'_> {
+        type Error = Infallible;
+    }
+
+    impl Write for Sink<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.0.borrow_mut().push(buf.to_vec());
+            Ok(buf.len())
+        }
+    }
+
+    /// Runs `logger` until `writes` holds `len` bytes, or a second has passed.
+    fn run_until_written(
+        logger: impl Future<Output = ()>,
+        writes: &RefCell<Vec<Vec<u8>>>,
+        len: usize,
+    ) {
+        let start = Instant::now();
+        let written = async {
+            while writes.borrow().concat().len() < len && start.elapsed() < Duration::from_secs(1) {
+                embassy_futures::yield_now().await;
+            }
+        };
+        embassy_futures::block_on(embassy_futures::select::select(logger, written));
+    }
+
+    #[test]
+    fn run_uart_writes_frames_in_chunks() {
+        let _lock = host::lock();
+        let writes = RefCell::new(Vec::new());
+        let frames = [vec![1; 40], vec![2; 40]].concat();
+        crate::write_raw_frame(&frames[..40]);
+        crate::write_raw_frame(&frames[40..]);
+        crate::request_flush();
+        run_until_written(run_uart(Sink(&writes), CONFIG), &writes, frames.len());
+        let writes = writes.into_inner();
+        assert_eq!(writes.concat(), frames);
+        assert_eq!(writes.iter().map(Vec::len).collect::<Vec<_>>(), [64, 16]);
+    }
+
+    #[test]
+    fn handshake_gives_the_chunk_size() {
+        let _lock = host::lock();
+        let writes = RefCell::new(Vec::new());
+        let config = FlushConfig {
+            send_handshake: true,
+            ..CONFIG
+        };
+        let frame = vec![1; 10];
+        crate::write_raw_frame(&frame);
+        crate::request_flush();
+        let transport = UartTransport::with_chunk_size(Sink(&writes), 32);
+        let len = crate::handshake::HANDSHAKE_LEN + frame.len();
+        run_until_written(logger_with_flush_config(transport, config), &writes, len);
+        assert_eq!(
+            writes.into_inner().concat(),
+            [&handshake(32)[..], &frame].concat()
+        );
+    }
+}